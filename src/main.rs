@@ -1,10 +1,21 @@
-use std::cmp;
+use std::cmp::{self, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use serde::{Deserialize, Serialize};
 use tcod::colors;
 use tcod::colors::*;
 use tcod::console::*;
 use tcod::map::{FovAlgorithm, Map as FovMap};
-use tcod::input::{self, Event, Key, Mouse};
-use rand::Rng;
+use tcod::input::{self, Event, Key, KeyCode, Mouse};
+use rand::distributions::{IndependentSample, Weighted, WeightedChoice};
+use rand::{Rng, SeedableRng, XorShiftRng};
 
 // Actual size of the window
 const SCREEN_WIDTH: i32 = 80;
@@ -21,16 +32,66 @@ const COLOUR_LIGHT_WALL: Color = Color { r: 130, g: 110, b: 50 };
 const COLOUR_DARK_GROUND: Color = Color { r: 50, g: 50, b: 150 };
 const COLOUR_LIGHT_GROUND: Color = Color { r: 200, g: 180, b: 50 };
 
+// Highlight of the tile under the cursor when targeting
+const COLOUR_VALID_TARGET: Color = Color { r: 0, g: 200, b: 0 };
+const COLOUR_INVALID_TARGET: Color = Color { r: 200, g: 0, b: 0 };
+// Highlight of the tile under the cursor in look mode
+const COLOUR_LOOK_CURSOR: Color = Color { r: 200, g: 200, b: 200 };
+
 // Parameters for dungeon generator
 const ROOM_MAX_SIZE: i32 = 10;
 const ROOM_MIN_SIZE: i32 = 6;
 const MAX_ROOMS: i32 = 30;
+// Smallest part of the map that binary space partitioning leaves for a room
+const BSP_MIN_LEAF_SIZE: i32 = 10;
+// Caves start as random noise with this many walls (in percent), smoothed
+// over a few steps
+const CAVE_WALL_CHANCE: u32 = 45;
+const CAVE_SMOOTHING_STEPS: u32 = 4;
+// Drunkard's walk levels dig out this much of the map (in percent), with
+// each walker taking up to this many steps
+const DRUNKARD_FLOOR_PERCENT: i32 = 40;
+const DRUNKARD_MAX_STEPS: u32 = 200;
+// Generated levels with less floor than this (in percent) are thrown away
+const MIN_FLOOR_PERCENT: i32 = 20;
+// Each level gets up to this many vaults, each with this chance (in
+// percent), tried at this many random spots
+const MAX_VAULTS: u32 = 2;
+const VAULT_CHANCE: u32 = 50;
+const VAULT_PLACEMENT_ATTEMPTS: u32 = 50;
+// Levels without rooms get their monsters and items in squares of this size,
+// which gives them about as many spawn areas as a level of rooms has rooms
+const SPAWN_AREA_SIZE: i32 = 15;
 
 const FOV_ALGO: FovAlgorithm = FovAlgorithm::Basic; // Default FOV algorithm
 const FOV_LIGHT_WALLS: bool = true; // Whether to light walls or not
 const TORCH_RADIUS: i32 = 10;
 
-const MAX_ROOM_MONSTERS: i32 = 3;
+// Item effects
+const HEAL_AMOUNT: i32 = 10;
+const LIGHTNING_DAMAGE: i32 = 20;
+const LIGHTNING_RANGE: i32 = 5;
+const CONFUSE_RANGE: i32 = 8;
+const CONFUSE_NUM_TURNS: i32 = 10;
+const FIREBALL_RADIUS: i32 = 3;
+const FIREBALL_DAMAGE: i32 = 12;
+
+// Where the game is saved, and the version of its format. Bump the version
+// whenever a saved type changes, so old save files are rejected cleanly.
+const SAVE_FILE: &str = "savegame";
+const SAVE_VERSION: u32 = 9;
+
+// Experience and level-ups
+const LEVEL_UP_BASE: i32 = 200;
+const LEVEL_UP_FACTOR: i32 = 150;
+const LEVEL_UP_HP: i32 = 10;
+
+// Monsters chasing the player look for paths of up to this many steps, and
+// count a tile with something blocking it as this many steps
+const MAX_PATH_LENGTH: u32 = 30;
+const OCCUPIED_TILE_COST: i32 = 10;
+// Monsters run away when their HP is down to this much (in percent)
+const FLEE_HP_PERCENT: i32 = 25;
 
 // Player will always be the first object
 const PLAYER: usize = 0;
@@ -43,6 +104,20 @@ const PANEL_Y: i32 = SCREEN_HEIGHT - PANEL_HEIGHT;
 const MSG_X: i32 = BAR_WIDTH + 2;
 const MSG_WIDTH: i32 = SCREEN_WIDTH - BAR_WIDTH - 2;
 const MSG_HEIGHT: usize = PANEL_HEIGHT as usize - 1;
+// How many messages are kept for the message log by default, forgetting the
+// oldest ones
+const MAX_MESSAGES: usize = 1000;
+
+const INVENTORY_WIDTH: i32 = 50;
+const MAIN_MENU_WIDTH: i32 = 24;
+const LEVEL_SCREEN_WIDTH: i32 = 40;
+const KEY_BINDINGS_WIDTH: i32 = 50;
+const LOOK_WIDTH: i32 = 30;
+const CHARACTER_SCREEN_WIDTH: i32 = 40;
+// Where the text of each message starts in the message log, after its turn
+const LOG_TEXT_X: i32 = 8;
+// One letter per slot, from 'a' to 'z'
+const MAX_INVENTORY_SIZE: usize = 26;
 
 struct Tcod {
     root: Root,
@@ -51,12 +126,13 @@ struct Tcod {
     fov: FovMap,
     key: Key,
     mouse: Mouse,
+    keymap: KeyMap,
 }
 
 /// This is a generic object: the player, a monster, an item, the stairs, etc…
 /// 
 /// It's always represented by a char on screen.
-#[derive(Debug)]
+#[derive(Debug, Serialize, Deserialize)]
 struct Object {
     x: i32,
     y: i32,
@@ -67,8 +143,14 @@ struct Object {
     /// If the object blocks the character or not
     blocks: bool,
     alive: bool,
+    /// If the object is still drawn once its tile is explored but out of FOV
+    always_visible: bool,
+    /// Character level, which goes up with experience
+    level: i32,
     fighter: Option<Fighter>,
     ai: Option<Ai>,
+    item: Option<Item>,
+    equipment: Option<Equipment>,
 }
 
 impl Object {
@@ -88,8 +170,12 @@ impl Object {
             name: name.into(),
             blocks,
             alive: false,
+            always_visible: false,
+            level: 1,
             fighter: None,
             ai: None,
+            item: None,
+            equipment: None,
         }
     }
 
@@ -116,7 +202,14 @@ impl Object {
         ((dx.pow(2) + dy.pow(2)) as f32).sqrt()
     }
 
-    pub fn take_damage(&mut self, damage: i32, game: &mut Game) {
+    /// Return the distance to some coordinates.
+    pub fn distance(&self, x: i32, y: i32) -> f32 {
+        (((x - self.x).pow(2) + (y - self.y).pow(2)) as f32).sqrt()
+    }
+
+    /// Returns the experience granted for killing the object, if the damage
+    /// killed it.
+    pub fn take_damage(&mut self, damage: i32, game: &mut Game) -> Option<i32> {
         // Apply damage if possible
         if let Some(fighter) = self.fighter.as_mut() {
             // Checks for damage even though attack() does so because you might
@@ -132,18 +225,15 @@ impl Object {
         if let Some(fighter) = self.fighter {
             if fighter.hp <= 0 {
                 self.alive = false;
-                fighter.on_death.callback(self, game);
+                return Some(fighter.on_death.callback(self, game));
             }
         }
+        None
     }
 
     pub fn attack(&mut self, target: &mut Object, game: &mut Game) {
         // A simple formula for attack damage
-        let damage = self
-            .fighter
-            .map_or(0, |f| f.power) - target
-            .fighter
-            .map_or(0, |f| f.defence);
+        let damage = self.power(game) - target.defence(game);
         if damage > 0 {
             // Make the target take some damage
             game.messages.add(
@@ -154,8 +244,14 @@ impl Object {
                     damage
                 ),
                 WHITE,
+                MessageCategory::Combat,
             );
-            target.take_damage(damage, game);
+            if let Some(xp) = target.take_damage(damage, game) {
+                // Yield experience to the killer
+                if let Some(fighter) = self.fighter.as_mut() {
+                    fighter.xp += xp;
+                }
+            }
         } else {
             game.messages.add(
                 format!(
@@ -164,13 +260,135 @@ impl Object {
                     target.name
                 ),
                 WHITE,
+                MessageCategory::Combat,
+            );
+        }
+    }
+
+    /// Return the attack power, including the bonuses of all equipped items
+    pub fn power(&self, game: &Game) -> i32 {
+        let base_power = self.fighter.map_or(0, |f| f.power);
+        let bonus: i32 = self
+            .get_all_equipped(game)
+            .iter()
+            .map(|e| e.power_bonus)
+            .sum();
+        base_power + bonus
+    }
+
+    /// Return the defence, including the bonuses of all equipped items
+    pub fn defence(&self, game: &Game) -> i32 {
+        let base_defence = self.fighter.map_or(0, |f| f.defence);
+        let bonus: i32 = self
+            .get_all_equipped(game)
+            .iter()
+            .map(|e| e.defence_bonus)
+            .sum();
+        base_defence + bonus
+    }
+
+    /// Return the maximum HP, including the bonuses of all equipped items
+    pub fn max_hp(&self, game: &Game) -> i32 {
+        let base_max_hp = self.fighter.map_or(0, |f| f.max_hp);
+        let bonus: i32 = self
+            .get_all_equipped(game)
+            .iter()
+            .map(|e| e.max_hp_bonus)
+            .sum();
+        base_max_hp + bonus
+    }
+
+    /// Heal by the given amount, without going over the maximum HP (including
+    /// equipment bonuses)
+    pub fn heal(&mut self, amount: i32, game: &Game) {
+        let max_hp = self.max_hp(game);
+        if let Some(fighter) = self.fighter.as_mut() {
+            fighter.heal(amount, max_hp);
+        }
+    }
+
+    /// Return the equipment of every equipped item. Only the player has an
+    /// inventory, so nobody else has anything equipped.
+    pub fn get_all_equipped(&self, game: &Game) -> Vec<Equipment> {
+        let is_player = self
+            .fighter
+            .is_some_and(|f| f.on_death == DeathCallback::Player);
+        if is_player {
+            game.inventory
+                .iter()
+                .filter_map(|item| item.equipment)
+                .filter(|equipment| equipment.equipped)
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+
+    /// Equip the object and show a message about it
+    pub fn equip(&mut self, messages: &mut Messages) {
+        if self.item.is_none() {
+            messages.add(
+                format!("Can't equip {:?} because it's not an Item.", self),
+                RED,
+                MessageCategory::Item,
+            );
+            return;
+        }
+        if let Some(equipment) = self.equipment.as_mut() {
+            if !equipment.equipped {
+                equipment.equipped = true;
+                messages.add(
+                    format!("Equipped {} on {}.", self.name, equipment.slot),
+                    LIGHT_GREEN,
+                    MessageCategory::Item,
+                );
+            }
+        } else {
+            messages.add(
+                format!(
+                    "Can't equip {:?} because it's not an Equipment.",
+                    self
+                ),
+                RED,
+                MessageCategory::Item,
+            );
+        }
+    }
+
+    /// Unequip the object and show a message about it
+    pub fn dequip(&mut self, messages: &mut Messages) {
+        if self.item.is_none() {
+            messages.add(
+                format!("Can't dequip {:?} because it's not an Item.", self),
+                RED,
+                MessageCategory::Item,
+            );
+            return;
+        }
+        if let Some(equipment) = self.equipment.as_mut() {
+            if equipment.equipped {
+                equipment.equipped = false;
+                messages.add(
+                    format!("Dequipped {} from {}.", self.name, equipment.slot),
+                    LIGHT_YELLOW,
+                    MessageCategory::Item,
+                );
+            }
+        } else {
+            messages.add(
+                format!(
+                    "Can't dequip {:?} because it's not an Equipment.",
+                    self
+                ),
+                RED,
+                MessageCategory::Item,
             );
         }
     }
 }
 
 /// A tile of the map and its properties
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 struct Tile {
     /// If the tile blocks anything from going through it
     blocked: bool,
@@ -200,9 +418,217 @@ impl Tile {
 
 type Map = Vec<Vec<Tile>>;
 
+#[derive(Serialize, Deserialize)]
 struct Game {
     map: Map,
     messages: Messages,
+    inventory: Vec<Object>,
+    /// How deep the player is in the dungeon, starting at 1
+    dungeon_level: u32,
+    /// The turn being played, starting at 1
+    turn: u32,
+    /// How many monsters have died
+    kills: u32,
+    rng: GameRng,
+}
+
+/// A game running without a window or any input: turns are stepped with
+/// `PlayerAction`s, and the game and its objects can be inspected in between.
+struct Simulation {
+    game: Game,
+    objects: Vec<Object>,
+    fov: FovMap,
+}
+
+impl Simulation {
+    pub fn new(seed: u32) -> Self {
+        let (game, objects) = new_game(seed);
+        Simulation::resume(game, objects)
+    }
+
+    /// Carry on with a game that is already going, like a loaded one
+    pub fn resume(mut game: Game, objects: Vec<Object>) -> Self {
+        let mut fov = FovMap::new(MAP_WIDTH, MAP_HEIGHT);
+        initialise_fov(&mut fov, &game.map);
+        compute_fov(&mut fov, &mut game.map, &objects);
+        Simulation { game, objects, fov }
+    }
+
+    pub fn step(&mut self, action: PlayerAction) -> TurnResult {
+        play_turn(action, &mut self.fov, &mut self.game, &mut self.objects)
+    }
+}
+
+/// The game's random number generator. Every random decision is drawn from
+/// it, so the same seed and the same inputs always give the same game.
+///
+/// The generator's state can't be saved directly, so the save file keeps the
+/// seed and how many numbers were drawn, and loading replays those draws.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "SavedRng", into = "SavedRng")]
+struct GameRng {
+    seed: u32,
+    draws: u64,
+    rng: XorShiftRng,
+}
+
+impl GameRng {
+    pub fn new(seed: u32) -> Self {
+        // The seed goes last, so that the generator's seed is never all zeros
+        GameRng {
+            seed,
+            draws: 0,
+            rng: XorShiftRng::from_seed([
+                0x193a_6754,
+                0xa8a7_d469,
+                0x9783_0e05,
+                seed,
+            ]),
+        }
+    }
+}
+
+impl Rng for GameRng {
+    fn next_u32(&mut self) -> u32 {
+        self.draws += 1;
+        self.rng.next_u32()
+    }
+}
+
+/// How the game's random number generator is written to the save file
+#[derive(Serialize, Deserialize)]
+struct SavedRng {
+    seed: u32,
+    draws: u64,
+}
+
+impl From<GameRng> for SavedRng {
+    fn from(rng: GameRng) -> Self {
+        SavedRng {
+            seed: rng.seed,
+            draws: rng.draws,
+        }
+    }
+}
+
+impl From<SavedRng> for GameRng {
+    fn from(saved: SavedRng) -> Self {
+        // Bring the generator back to where it was by replaying the draws
+        let mut rng = GameRng::new(saved.seed);
+        for _ in 0..saved.draws {
+            rng.next_u32();
+        }
+        rng
+    }
+}
+
+/// How far every tile is from the nearest of some source tiles, going around
+/// walls. Each source starts at its own value instead of 0, which makes some
+/// sources more attractive than others.
+///
+/// Stepping "downhill", to a neighbouring tile with a lower value, leads to
+/// the nearest (or most attractive) source, so one map can guide any number
+/// of monsters.
+struct DijkstraMap {
+    /// Each tile's value, or `None` if it can't be reached
+    values: Vec<Vec<Option<i32>>>,
+}
+
+impl DijkstraMap {
+    /// Spread out from the sources over the tiles that can be passed, each
+    /// step adding 1
+    pub fn new(
+        sources: &[((i32, i32), i32)],
+        passable: impl Fn(i32, i32) -> bool,
+    ) -> Self {
+        let mut values =
+            vec![vec![None; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        let mut open = BinaryHeap::new();
+        for &((x, y), value) in sources {
+            let best = &mut values[x as usize][y as usize];
+            if best.is_none_or(|best| value < best) {
+                *best = Some(value);
+                open.push(Reverse((value, (x, y))));
+            }
+        }
+
+        // Always carry on from the lowest tile, so each is only finished once
+        while let Some(Reverse((value, (x, y)))) = open.pop() {
+            if values[x as usize][y as usize].is_some_and(|best| value > best) {
+                continue;
+            }
+            for (nx, ny) in neighbours(x, y) {
+                if !passable(nx, ny) {
+                    continue;
+                }
+                let best = &mut values[nx as usize][ny as usize];
+                if best.is_none_or(|best| value + 1 < best) {
+                    *best = Some(value + 1);
+                    open.push(Reverse((value + 1, (nx, ny))));
+                }
+            }
+        }
+
+        DijkstraMap { values }
+    }
+
+    /// A map for getting away from this map's sources. Its values are this
+    /// map's turned around and scaled up, then spread out again, so it leads
+    /// along ways that keep going further away instead of into the nearest
+    /// dead end.
+    pub fn fleeing(&self, passable: impl Fn(i32, i32) -> bool) -> Self {
+        let mut sources = vec![];
+        for x in 0..MAP_WIDTH {
+            for y in 0..MAP_HEIGHT {
+                if let Some(value) = self.values[x as usize][y as usize] {
+                    sources.push(((x, y), -value * 6 / 5));
+                }
+            }
+        }
+        DijkstraMap::new(&sources, passable)
+    }
+
+    /// The lowest neighbouring tile that nothing blocks, if it's lower than
+    /// the given tile
+    pub fn downhill(
+        &self,
+        x: i32,
+        y: i32,
+        map: &Map,
+        objects: &[Object],
+    ) -> Option<(i32, i32)> {
+        let here = self.values[x as usize][y as usize]?;
+        neighbours(x, y)
+            .filter_map(|(nx, ny)| {
+                let value = self.values[nx as usize][ny as usize]?;
+                Some((value, (nx, ny)))
+            })
+            .filter(|&(value, (nx, ny))| {
+                value < here && !is_blocked(nx, ny, map, objects)
+            })
+            .min_by_key(|&(value, _)| value)
+            .map(|(_, tile)| tile)
+    }
+}
+
+/// The Dijkstra maps monsters share each turn, instead of each one searching
+/// for its own way
+struct MonsterMaps {
+    to_player: DijkstraMap,
+    away_from_player: DijkstraMap,
+}
+
+impl MonsterMaps {
+    pub fn new(map: &Map, objects: &[Object]) -> Self {
+        let passable = |x: i32, y: i32| !map[x as usize][y as usize].blocked;
+        let player = objects[PLAYER].pos();
+        let to_player = DijkstraMap::new(&[(player, 0)], passable);
+        let away_from_player = to_player.fleeing(passable);
+        MonsterMaps {
+            to_player,
+            away_from_player,
+        }
+    }
 }
 
 /// A rectangle on the map, used to characterize a room.
@@ -230,6 +656,17 @@ impl Rect {
         (centre_x, centre_y)
     }
 
+    /// The tiles inside the room's walls, which `create_room` makes passable
+    pub fn inner_tiles(&self) -> Vec<(i32, i32)> {
+        let mut tiles = Vec::new();
+        for x in (self.x1 + 1)..self.x2 {
+            for y in (self.y1 + 1)..self.y2 {
+                tiles.push((x, y));
+            }
+        }
+        tiles
+    }
+
     pub fn intersects_with(&self, other: &Rect) -> bool {
         // Returns true if this rectangle intersects with another one
         (self.x1 <= other.x2)
@@ -239,158 +676,1832 @@ impl Rect {
     }
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-enum PlayerAction {
-    TookTurn,
-    DidntTakeTurn,
-    Exit,
+/// A way of generating the dungeon's levels. Generators only lay out the map;
+/// the player, stairs, monsters and items are put on it afterwards, at the
+/// places the generator picked.
+trait MapGenerator {
+    fn generate(&self, rng: &mut GameRng) -> GeneratedMap;
 }
 
-/// Combat-related properties and methods (monster, player, NPC, etc)
-#[derive(Clone, Copy, Debug, PartialEq)]
-struct Fighter {
-    max_hp: i32,
-    hp: i32,
-    defence: i32,
-    power: i32,
-    on_death: DeathCallback,
+/// A freshly generated level, with nothing on it yet
+struct GeneratedMap {
+    map: Map,
+    /// Where the player starts the level
+    player_start: (i32, i32),
+    /// Where the stairs down to the next level go
+    stairs: (i32, i32),
+    /// The rooms, if the level has any (caves and tunnels don't)
+    rooms: Vec<Rect>,
+    /// Groups of floor tiles that each get their own monsters and items, like
+    /// the inside of each room
+    spawn_areas: Vec<Vec<(i32, i32)>>,
 }
 
-/// Monster Artificial Intelligence
-#[derive(Clone, Debug, PartialEq)]
-enum Ai {
-    Basic,
+/// Rooms of random sizes placed at random where they fit, each one joined to
+/// the previous one by a tunnel
+struct RoomsAndCorridors {
+    /// How many rooms to try to place, most of which won't fit
+    max_rooms: i32,
+    room_min_size: i32,
+    room_max_size: i32,
 }
 
-#[derive(Clone, Copy, Debug, PartialEq)]
-enum DeathCallback {
-    Player,
-    Monster,
-}
+impl MapGenerator for RoomsAndCorridors {
+    fn generate(&self, rng: &mut GameRng) -> GeneratedMap {
+        // Fill map with "blocked" tiles
+        let mut map = vec![
+            vec![Tile::wall(); MAP_HEIGHT as usize];
+            MAP_WIDTH as usize
+        ];
 
-impl DeathCallback {
-    fn callback(self, object: &mut Object, game: &mut Game) {
-        use DeathCallback::*;
-        let callback = match self {
-            Player => player_death,
-            Monster => monster_death,
-        };
-        callback(object, game);
+        let mut rooms: Vec<Rect> = Vec::new();
+
+        for _ in 0..self.max_rooms {
+            // Random width and height
+            let w = rng.gen_range(self.room_min_size, self.room_max_size + 1);
+            let h = rng.gen_range(self.room_min_size, self.room_max_size + 1);
+            // Random position without going out of the boundaries of the map
+            let x = rng.gen_range(0, MAP_WIDTH - w);
+            let y = rng.gen_range(0, MAP_HEIGHT - h);
+
+            let new_room = Rect::new(x, y, w, h);
+
+            // Run through the other rooms and see if they intersect with this
+            // one
+            let failed = rooms
+                .iter()
+                .any(|other_room| new_room.intersects_with(other_room));
+            if failed {
+                continue;
+            }
+
+            // This means that there are no intersections, so this room is
+            // valid. "Paint" it to the map's tiles.
+            create_room(new_room, &mut map);
+
+            if let Some(previous_room) = rooms.last() {
+                // All rooms after the first: connect it to the previous room
+                // with a tunnel between their centres
+                let (prev_x, prev_y) = previous_room.centre();
+                let (new_x, new_y) = new_room.centre();
+
+                // Toss a coin (random bool value – either true or false)
+                if rng.gen::<bool>() {
+                    // First move horizontally, then vertically
+                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
+                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
+                } else {
+                    // First move vertically, then horizontally
+                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
+                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
+                }
+            }
+
+            rooms.push(new_room);
+        }
+
+        // The player starts in the first room, and the stairs are in the last
+        GeneratedMap {
+            map,
+            player_start: rooms[0].centre(),
+            stairs: rooms[rooms.len() - 1].centre(),
+            spawn_areas: rooms.iter().map(Rect::inner_tiles).collect(),
+            rooms,
+        }
     }
 }
 
-struct Messages {
-    messages: Vec<(String, Color)>,
+/// Binary space partitioning: the map is split in two, and each part split in
+/// two again, until the parts are too small to split. Each of the smallest
+/// parts gets a room, and the two parts of every split are joined by a
+/// tunnel, so the rooms are spread evenly over the whole map.
+struct Bsp {
+    /// Parts are only split if both halves are at least this big
+    min_leaf_size: i32,
+    room_min_size: i32,
 }
 
-impl Messages {
-    pub fn new() -> Self {
-        Self { messages: Vec::new() }
-    }
+impl MapGenerator for Bsp {
+    fn generate(&self, rng: &mut GameRng) -> GeneratedMap {
+        // Fill map with "blocked" tiles
+        let mut map = vec![
+            vec![Tile::wall(); MAP_HEIGHT as usize];
+            MAP_WIDTH as usize
+        ];
 
-    /// Add the new message as a tuple, with the text and the colour
-    pub fn add<T: Into<String>>(&mut self, message: T, colour: Color) {
-        self.messages.push((message.into(), colour));
-    }
+        let mut rooms = Vec::new();
+        let whole_map = Rect::new(0, 0, MAP_WIDTH - 1, MAP_HEIGHT - 1);
+        self.split(whole_map, &mut map, &mut rooms, rng);
 
-    /// Create a `DoubleEndedIterator` over the messages
-    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &(String, Color)> {
-        self.messages.iter()
+        // The rooms are listed from one side of the map to the other, so the
+        // player and the stairs are far apart
+        GeneratedMap {
+            map,
+            player_start: rooms[0].centre(),
+            stairs: rooms[rooms.len() - 1].centre(),
+            spawn_areas: rooms.iter().map(Rect::inner_tiles).collect(),
+            rooms,
+        }
     }
 }
 
-fn main() {
-    let root = Root::initializer()
-        .font("arial10x10.png", FontLayout::Tcod)
-        .font_type(FontType::Greyscale)
-        .size(SCREEN_WIDTH, SCREEN_HEIGHT)
-        .title("RustyRogue")
-        .init();
+impl Bsp {
+    /// Split the leaf as far as it goes, adding a room to every part and
+    /// joining them up.
+    ///
+    /// Returns one of the rooms in the leaf, to join it to its sibling.
+    fn split(
+        &self,
+        leaf: Rect,
+        map: &mut Map,
+        rooms: &mut Vec<Rect>,
+        rng: &mut GameRng,
+    ) -> Rect {
+        let width = leaf.x2 - leaf.x1;
+        let height = leaf.y2 - leaf.y1;
+        let can_split_x = width >= 2 * self.min_leaf_size;
+        let can_split_y = height >= 2 * self.min_leaf_size;
 
-    let mut tcod = Tcod {
-        root,
-        con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
-        panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
-        fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
-        key: Default::default(),
-        mouse: Default::default(),
-    };
+        let split_x = match (can_split_x, can_split_y) {
+            (false, false) => {
+                // Too small to split, so this is where a room goes
+                let w = rng.gen_range(self.room_min_size, width + 1);
+                let h = rng.gen_range(self.room_min_size, height + 1);
+                let x = rng.gen_range(leaf.x1, leaf.x2 - w + 1);
+                let y = rng.gen_range(leaf.y1, leaf.y2 - h + 1);
+                let room = Rect::new(x, y, w, h);
+                create_room(room, map);
+                rooms.push(room);
+                return room;
+            },
+            (true, false) => true,
+            (false, true) => false,
+            // Cut across the longer side, so that the parts don't end up long
+            // and thin
+            (true, true) if width * 4 > height * 5 => true,
+            (true, true) if height * 4 > width * 5 => false,
+            (true, true) => rng.gen::<bool>(),
+        };
 
-    tcod::system::set_fps(LIMIT_FPS);
+        let (first, second) = if split_x {
+            let x = rng.gen_range(
+                leaf.x1 + self.min_leaf_size,
+                leaf.x2 - self.min_leaf_size + 1,
+            );
+            (
+                Rect::new(leaf.x1, leaf.y1, x - leaf.x1, height),
+                Rect::new(x, leaf.y1, leaf.x2 - x, height),
+            )
+        } else {
+            let y = rng.gen_range(
+                leaf.y1 + self.min_leaf_size,
+                leaf.y2 - self.min_leaf_size + 1,
+            );
+            (
+                Rect::new(leaf.x1, leaf.y1, width, y - leaf.y1),
+                Rect::new(leaf.x1, y, width, leaf.y2 - y),
+            )
+        };
 
-    let centre_x = SCREEN_WIDTH / 2;
-    let centre_y = SCREEN_HEIGHT / 2;
+        // Join the two parts with a tunnel between one room from each
+        let first_room = self.split(first, map, rooms, rng);
+        let second_room = self.split(second, map, rooms, rng);
+        let (first_x, first_y) = first_room.centre();
+        let (second_x, second_y) = second_room.centre();
+        if rng.gen::<bool>() {
+            create_h_tunnel(first_x, second_x, first_y, map);
+            create_v_tunnel(first_y, second_y, second_x, map);
+        } else {
+            create_v_tunnel(first_y, second_y, first_x, map);
+            create_h_tunnel(first_x, second_x, second_y, map);
+        }
 
-    // Create the object representing the player
-    let mut player = Object::new(
-        0,
-        0,
-        '@',
-        WHITE,
-        "Player",
-        true,
-    );
-    player.alive = true;
-    player.fighter = Some(Fighter {
-        max_hp: 30,
-        hp: 30,
-        defence: 2,
-        power: 5,
-        on_death: DeathCallback::Player,
-    });
+        // Either room will do to join this leaf to the rest
+        if rng.gen::<bool>() {
+            first_room
+        } else {
+            second_room
+        }
+    }
+}
 
-    // Create an NPC
-    let mut npc = Object::new(
-        centre_x - 5,
-        centre_y,
-        '@',
-        YELLOW,
-        "NPC",
-        true,
-    );
-    npc.alive = true;
+/// Caves grown with a cellular automaton: the map starts as random noise,
+/// which is smoothed over a few steps into open caverns. Only the biggest
+/// cave is kept, so every part of the level can be reached.
+struct Caves {
+    /// Chance (in percent) of each tile starting out as a wall
+    initial_wall_chance: u32,
+    /// How many times the noise is smoothed
+    smoothing_steps: u32,
+}
 
-    // The list of objects with those two
-    let mut objects = vec![player, npc];
+impl MapGenerator for Caves {
+    fn generate(&self, rng: &mut GameRng) -> GeneratedMap {
+        let on_border = |x: i32, y: i32| {
+            x == 0 || y == 0 || x == MAP_WIDTH - 1 || y == MAP_HEIGHT - 1
+        };
 
-    let mut game = Game {
-        // Generate map (at this point it's not drawn on the screen)
-        map: make_map(&mut objects),
-        messages: Messages::new(),
-    };
+        // Start with random noise, walled in by the map's border
+        let mut walls =
+            vec![vec![true; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+        for x in 0..MAP_WIDTH {
+            for y in 0..MAP_HEIGHT {
+                walls[x as usize][y as usize] = on_border(x, y)
+                    || rng.gen_range(0, 100) < self.initial_wall_chance;
+            }
+        }
 
-    // Populate the FOV map, according to the generated map
-    for y in 0..MAP_HEIGHT {
+        // Each step, a tile becomes a wall if most of the tiles around it
+        // (and itself) are walls, and floor otherwise
+        for _ in 0..self.smoothing_steps {
+            let previous = walls.clone();
+            for x in 1..(MAP_WIDTH - 1) {
+                for y in 1..(MAP_HEIGHT - 1) {
+                    let mut nearby_walls = 0;
+                    for nx in (x - 1)..=(x + 1) {
+                        for ny in (y - 1)..=(y + 1) {
+                            if previous[nx as usize][ny as usize] {
+                                nearby_walls += 1;
+                            }
+                        }
+                    }
+                    walls[x as usize][y as usize] = nearby_walls >= 5;
+                }
+            }
+        }
+
+        let mut map = vec![
+            vec![Tile::wall(); MAP_HEIGHT as usize];
+            MAP_WIDTH as usize
+        ];
         for x in 0..MAP_WIDTH {
-            tcod.fov.set(
-                x,
-                y,
-                !game.map[x as usize][y as usize].block_sight,
-                !game.map[x as usize][y as usize].blocked,
-            );
+            for y in 0..MAP_HEIGHT {
+                if !walls[x as usize][y as usize] {
+                    map[x as usize][y as usize] = Tile::empty();
+                }
+            }
         }
-    }
 
-    // Force FOV "recompute" first time through game loop
-    let mut previous_player_position = (-1, -1);
+        // Fill in every cave but the biggest one
+        let cave = keep_largest_region(&mut map);
 
-    game.messages.add(
+        // Start somewhere random, and put the stairs as far away as possible
+        let player_start = *rng.choose(&cave).unwrap_or(&(1, 1));
+        let reachable = flood_fill(&map, player_start);
+        let stairs = *reachable.last().unwrap_or(&player_start);
+
+        GeneratedMap {
+            map,
+            player_start,
+            stairs,
+            rooms: vec![],
+            spawn_areas: split_into_areas(&cave, SPAWN_AREA_SIZE),
+        }
+    }
+}
+
+/// Tunnels dug by "drunk" walkers staggering around at random. Each one sets
+/// off from a tile that was already dug, so every tunnel is connected, and
+/// walkers keep going until enough of the map is floor.
+struct DrunkardsWalk {
+    /// How much of the map (in percent) to dig out
+    floor_percent: i32,
+    /// How far each walker staggers before the next one sets off
+    max_steps: u32,
+}
+
+impl MapGenerator for DrunkardsWalk {
+    fn generate(&self, rng: &mut GameRng) -> GeneratedMap {
+        // Fill map with "blocked" tiles
+        let mut map = vec![
+            vec![Tile::wall(); MAP_HEIGHT as usize];
+            MAP_WIDTH as usize
+        ];
+
+        let player_start = (MAP_WIDTH / 2, MAP_HEIGHT / 2);
+        map[player_start.0 as usize][player_start.1 as usize] = Tile::empty();
+        let mut floor = vec![player_start];
+
+        let map_size = MAP_WIDTH * MAP_HEIGHT;
+        let target = (self.floor_percent * map_size / 100) as usize;
+        while floor.len() < target {
+            let (mut x, mut y) = *rng.choose(&floor).unwrap_or(&player_start);
+            for _ in 0..self.max_steps {
+                let (dx, dy) = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                    [rng.gen_range(0, 4)];
+                // Stay off the map's border, so that it's always walled in
+                x = (x + dx).clamp(1, MAP_WIDTH - 2);
+                y = (y + dy).clamp(1, MAP_HEIGHT - 2);
+                if map[x as usize][y as usize].blocked {
+                    map[x as usize][y as usize] = Tile::empty();
+                    floor.push((x, y));
+                    if floor.len() >= target {
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Put the stairs as far from the start as possible
+        let reachable = flood_fill(&map, player_start);
+        let stairs = *reachable.last().unwrap_or(&player_start);
+
+        GeneratedMap {
+            map,
+            player_start,
+            stairs,
+            rooms: vec![],
+            spawn_areas: split_into_areas(&floor, SPAWN_AREA_SIZE),
+        }
+    }
+}
+
+/// Rooms laid out by binary space partitioning, with caves breaking through
+/// a band across part of the map. Caves that don't reach the rooms are filled
+/// back in.
+struct Hybrid {
+    rooms: Bsp,
+    caves: Caves,
+}
+
+impl MapGenerator for Hybrid {
+    fn generate(&self, rng: &mut GameRng) -> GeneratedMap {
+        let rooms = self.rooms.generate(rng);
+        let caves = self.caves.generate(rng);
+
+        // Open up the caves over a band as wide as half the map
+        let mut map = rooms.map;
+        let band_start = rng.gen_range(0, MAP_WIDTH / 2 + 1);
+        for x in band_start..(band_start + MAP_WIDTH / 2) {
+            for y in 0..MAP_HEIGHT {
+                if !caves.map[x as usize][y as usize].blocked {
+                    map[x as usize][y as usize] = Tile::empty();
+                }
+            }
+        }
+        let floor = keep_largest_region(&mut map);
+
+        GeneratedMap {
+            map,
+            player_start: rooms.player_start,
+            stairs: rooms.stairs,
+            rooms: rooms.rooms,
+            spawn_areas: split_into_areas(&floor, SPAWN_AREA_SIZE),
+        }
+    }
+}
+
+/// The kinds of level the dungeon is made of
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum LevelStyle {
+    Rooms,
+    Bsp,
+    Caves,
+    /// Drunkard's walk tunnels
+    Tunnels,
+    /// Rooms with caves through part of them
+    Hybrid,
+}
+
+/// A hand-designed room, stamped into solid rock on generated levels and
+/// joined to the rest of the level by a tunnel from its entrance.
+///
+/// The layout is drawn as rows of text of the same width:
+///
+/// - `#` is wall and `.` is floor, while a space leaves the rock as it is
+/// - `+` is the entrance, where the tunnel starts
+/// - `O` is an orc, `T` a troll, and `M` any monster found at that depth
+/// - `!` is a healing potion, `/` a sword, `[` a shield, `]` leather armour,
+///   and `?` any item found at that depth
+struct Vault {
+    /// The first and last dungeon levels the vault can appear on
+    min_level: u32,
+    max_level: u32,
+    rows: &'static [&'static str],
+}
+
+impl Vault {
+    /// The vault's layout, turned clockwise by the given number of quarter
+    /// turns and then, optionally, mirrored left to right
+    fn layout(&self, quarter_turns: u32, mirrored: bool) -> Vec<Vec<char>> {
+        let mut layout: Vec<Vec<char>> = self
+            .rows
+            .iter()
+            .map(|row| row.chars().collect())
+            .collect();
+        for _ in 0..quarter_turns {
+            // Each new row is a column of the old layout, read bottom to top
+            let height = layout.len();
+            let width = layout[0].len();
+            layout = (0..width)
+                .map(|column| {
+                    (0..height).rev().map(|row| layout[row][column]).collect()
+                })
+                .collect();
+        }
+        if mirrored {
+            for row in &mut layout {
+                row.reverse();
+            }
+        }
+        layout
+    }
+}
+
+const VAULTS: &[Vault] = &[
+    // A guard room, with a potion for whoever gets through
+    Vault {
+        min_level: 1,
+        max_level: 4,
+        rows: &[
+            "#######",
+            "#O...O#",
+            "#..!..#",
+            "#O...O#",
+            "###+###",
+        ],
+    },
+    // A shrine, with its offerings
+    Vault {
+        min_level: 2,
+        max_level: 7,
+        rows: &[
+            "  ###  ",
+            " ##!## ",
+            "##...##",
+            "#..?..#",
+            "##...##",
+            " ##+## ",
+        ],
+    },
+    // An armoury, kept by a troll
+    Vault {
+        min_level: 3,
+        max_level: 9,
+        rows: &[
+            "#########",
+            "#/#...#[#",
+            "#.#.T.#.#",
+            "#.......#",
+            "####+####",
+        ],
+    },
+    // A den, deep down, full of whatever lives there
+    Vault {
+        min_level: 5,
+        max_level: u32::MAX,
+        rows: &[
+            " ####### ",
+            "##M...M##",
+            "#...?...#",
+            "#.M.]...#",
+            "##M...M##",
+            " ###+### ",
+        ],
+    },
+];
+
+/// Whether the player's action used up their turn, so the monsters get theirs
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TurnResult {
+    TookTurn,
+    DidntTakeTurn,
+}
+
+/// Something the player does in the game. The interface turns keys, menus and
+/// targeting into these, and headless mode reads them from a script, so both
+/// go through the same turn logic.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum PlayerAction {
+    /// Move by the given amount (one tile, possibly diagonally), or attack
+    /// whatever is in the way
+    MoveOrAttack(i32, i32),
+    /// Do nothing for a turn
+    Wait,
+    PickUp,
+    /// Use the item in the given inventory slot (on the target, if it needs
+    /// one)
+    UseItem(usize, Option<Target>),
+    /// Drop the item in the given inventory slot
+    DropItem(usize),
+    /// Go down the stairs the player is standing on
+    Descend,
+    /// Spend experience to gain a level, raising the given stat
+    LevelUp(Stat),
+    /// Take a step towards the given explored tile
+    Travel(i32, i32),
+    /// Take a step towards the nearest tile that hasn't been explored yet
+    Explore,
+}
+
+impl PlayerAction {
+    /// Whether the action is repeated, a step each turn, until it stops
+    /// taking turns (because it is done, or something came into view)
+    pub fn repeats(&self) -> bool {
+        matches!(self, PlayerAction::Travel(..) | PlayerAction::Explore)
+    }
+}
+
+impl FromStr for PlayerAction {
+    type Err = String;
+
+    /// Read an action from a line of a headless script, like `move 1 0`,
+    /// `use 0 tile 12 5` or `levelup strength`
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        use PlayerAction::*;
+
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            ["move", dx, dy] => {
+                let dx: i32 = parse_number(dx)?;
+                let dy: i32 = parse_number(dy)?;
+                if dx.abs() > 1 || dy.abs() > 1 {
+                    return Err(format!("Moves are one tile at most: {}", line));
+                }
+                Ok(MoveOrAttack(dx, dy))
+            },
+            ["wait"] => Ok(Wait),
+            ["pickup"] => Ok(PickUp),
+            ["use", slot] => Ok(UseItem(parse_number(slot)?, None)),
+            ["use", slot, "tile", x, y] => {
+                let target = Target::Tile(parse_number(x)?, parse_number(y)?);
+                Ok(UseItem(parse_number(slot)?, Some(target)))
+            },
+            ["use", slot, "monster", id] => {
+                let target = Target::Monster(parse_number(id)?);
+                Ok(UseItem(parse_number(slot)?, Some(target)))
+            },
+            ["drop", slot] => Ok(DropItem(parse_number(slot)?)),
+            ["descend"] => Ok(Descend),
+            ["travel", x, y] => Ok(Travel(parse_number(x)?, parse_number(y)?)),
+            ["explore"] => Ok(Explore),
+            ["levelup", "constitution"] => Ok(LevelUp(Stat::Constitution)),
+            ["levelup", "strength"] => Ok(LevelUp(Stat::Strength)),
+            ["levelup", "agility"] => Ok(LevelUp(Stat::Agility)),
+            _ => Err(format!("Unknown action: {}", line)),
+        }
+    }
+}
+
+fn parse_number<T: FromStr>(word: &str) -> Result<T, String> {
+    word.parse().map_err(|_| format!("Invalid number: {}", word))
+}
+
+/// The stats the player can raise when they level up
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stat {
+    /// More maximum HP
+    Constitution,
+    /// More attack power
+    Strength,
+    /// More defence
+    Agility,
+}
+
+/// The states of the main loop while a game is being played. Each frame is
+/// handled by the current state, which also decides the next one.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum GameState {
+    /// Leave the game (saving it) and go back to the main menu
+    MainMenu,
+    Playing,
+    InventoryOpen(InventoryAction),
+    /// Choosing a target for the item in the given inventory slot
+    Targeting(usize),
+    /// Repeating the action every frame, until it's done or a key is pressed
+    Repeating(PlayerAction),
+    Dead,
+}
+
+/// What choosing an item in the inventory menu does with it
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InventoryAction {
+    Use,
+    Drop,
+}
+
+/// The commands that keys can be bound to
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Command {
+    MoveN,
+    MoveNE,
+    MoveE,
+    MoveSE,
+    MoveS,
+    MoveSW,
+    MoveW,
+    MoveNW,
+    Wait,
+    PickUp,
+    Inventory,
+    Drop,
+    Descend,
+    Explore,
+    Look,
+    CharacterSheet,
+    MessageLog,
+    KeyBindings,
+}
+
+impl Command {
+    /// Every command, in the order they are listed on the key bindings screen
+    const ALL: [Command; 18] = [
+        Command::MoveN,
+        Command::MoveNE,
+        Command::MoveE,
+        Command::MoveSE,
+        Command::MoveS,
+        Command::MoveSW,
+        Command::MoveW,
+        Command::MoveNW,
+        Command::Wait,
+        Command::PickUp,
+        Command::Inventory,
+        Command::Drop,
+        Command::Descend,
+        Command::Explore,
+        Command::Look,
+        Command::CharacterSheet,
+        Command::MessageLog,
+        Command::KeyBindings,
+    ];
+
+    /// The command's name in the key bindings file
+    pub fn name(self) -> &'static str {
+        use Command::*;
+        match self {
+            MoveN => "move_n",
+            MoveNE => "move_ne",
+            MoveE => "move_e",
+            MoveSE => "move_se",
+            MoveS => "move_s",
+            MoveSW => "move_sw",
+            MoveW => "move_w",
+            MoveNW => "move_nw",
+            Wait => "wait",
+            PickUp => "pick_up",
+            Inventory => "inventory",
+            Drop => "drop",
+            Descend => "descend",
+            Explore => "explore",
+            Look => "look",
+            CharacterSheet => "character_sheet",
+            MessageLog => "message_log",
+            KeyBindings => "key_bindings",
+        }
+    }
+
+    /// The direction a movement command moves in
+    pub fn direction(self) -> Option<(i32, i32)> {
+        use Command::*;
+        match self {
+            MoveN => Some((0, -1)),
+            MoveNE => Some((1, -1)),
+            MoveE => Some((1, 0)),
+            MoveSE => Some((1, 1)),
+            MoveS => Some((0, 1)),
+            MoveSW => Some((-1, 1)),
+            MoveW => Some((-1, 0)),
+            MoveNW => Some((-1, -1)),
+            _ => None,
+        }
+    }
+}
+
+/// A key that a command can be bound to: either a special key, or a key
+/// that types a character
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum BoundKey {
+    Code(KeyCode),
+    Char(char),
+}
+
+/// The special keys that can be bound, by their names in the key bindings
+/// file
+const KEY_NAMES: &[(&str, KeyCode)] = &[
+    ("Up", KeyCode::Up),
+    ("Down", KeyCode::Down),
+    ("Left", KeyCode::Left),
+    ("Right", KeyCode::Right),
+    ("Home", KeyCode::Home),
+    ("End", KeyCode::End),
+    ("PageUp", KeyCode::PageUp),
+    ("PageDown", KeyCode::PageDown),
+    ("Insert", KeyCode::Insert),
+    ("Delete", KeyCode::Delete),
+    ("Tab", KeyCode::Tab),
+    ("Backspace", KeyCode::Backspace),
+    ("Space", KeyCode::Spacebar),
+    ("NumPad0", KeyCode::NumPad0),
+    ("NumPad1", KeyCode::NumPad1),
+    ("NumPad2", KeyCode::NumPad2),
+    ("NumPad3", KeyCode::NumPad3),
+    ("NumPad4", KeyCode::NumPad4),
+    ("NumPad5", KeyCode::NumPad5),
+    ("NumPad6", KeyCode::NumPad6),
+    ("NumPad7", KeyCode::NumPad7),
+    ("NumPad8", KeyCode::NumPad8),
+    ("NumPad9", KeyCode::NumPad9),
+    ("F1", KeyCode::F1),
+    ("F2", KeyCode::F2),
+    ("F3", KeyCode::F3),
+    ("F4", KeyCode::F4),
+    ("F5", KeyCode::F5),
+    ("F6", KeyCode::F6),
+    ("F7", KeyCode::F7),
+    ("F8", KeyCode::F8),
+    ("F9", KeyCode::F9),
+    ("F10", KeyCode::F10),
+    ("F11", KeyCode::F11),
+    ("F12", KeyCode::F12),
+];
+
+impl BoundKey {
+    /// Read a key from the key bindings file: the name of a special key, or a
+    /// single character
+    pub fn parse(name: &str) -> Option<BoundKey> {
+        let special = KEY_NAMES
+            .iter()
+            .find(|(key_name, _)| key_name.eq_ignore_ascii_case(name));
+        if let Some(&(_, code)) = special {
+            return Some(BoundKey::Code(code));
+        }
+        let mut chars = name.chars();
+        match (chars.next(), chars.next()) {
+            (Some(char), None) => Some(BoundKey::Char(char)),
+            _ => None,
+        }
+    }
+
+    pub fn matches(self, key: Key) -> bool {
+        match self {
+            BoundKey::Code(code) => key.code == code,
+            BoundKey::Char(char) => {
+                key.code == KeyCode::Text && key.text().chars().eq([char])
+            },
+        }
+    }
+}
+
+impl fmt::Display for BoundKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BoundKey::Code(code) => {
+                let name = KEY_NAMES
+                    .iter()
+                    .find(|&&(_, key_code)| key_code == code)
+                    .map_or("?", |&(name, _)| name);
+                write!(f, "{}", name)
+            },
+            BoundKey::Char(char) => write!(f, "{}", char),
+        }
+    }
+}
+
+/// Which keys are bound to which commands. Escape (back to the main menu) and
+/// Alt+Enter (fullscreen) always do the same thing, so they can't be bound.
+struct KeyMap {
+    bindings: Vec<(Command, Vec<BoundKey>)>,
+}
+
+impl KeyMap {
+    /// The bindings used unless the key bindings file changes them
+    pub fn defaults() -> Self {
+        use BoundKey::*;
+        use Command::*;
+        KeyMap {
+            bindings: vec![
+                (
+                    MoveN,
+                    vec![
+                        Code(KeyCode::Up),
+                        Code(KeyCode::NumPad8),
+                        Char('k'),
+                    ],
+                ),
+                (MoveNE, vec![Code(KeyCode::NumPad9), Char('u')]),
+                (
+                    MoveE,
+                    vec![
+                        Code(KeyCode::Right),
+                        Code(KeyCode::NumPad6),
+                        Char('l'),
+                    ],
+                ),
+                (MoveSE, vec![Code(KeyCode::NumPad3), Char('n')]),
+                (
+                    MoveS,
+                    vec![
+                        Code(KeyCode::Down),
+                        Code(KeyCode::NumPad2),
+                        Char('j'),
+                    ],
+                ),
+                (MoveSW, vec![Code(KeyCode::NumPad1), Char('b')]),
+                (
+                    MoveW,
+                    vec![
+                        Code(KeyCode::Left),
+                        Code(KeyCode::NumPad4),
+                        Char('h'),
+                    ],
+                ),
+                (MoveNW, vec![Code(KeyCode::NumPad7), Char('y')]),
+                (Wait, vec![Code(KeyCode::NumPad5), Char('.')]),
+                (PickUp, vec![Char('g')]),
+                (Inventory, vec![Char('i')]),
+                (Drop, vec![Char('d')]),
+                (Descend, vec![Char('>')]),
+                (Explore, vec![Char('x')]),
+                (Look, vec![Char(';')]),
+                (CharacterSheet, vec![Char('c')]),
+                (MessageLog, vec![Char('m')]),
+                (KeyBindings, vec![Char('?')]),
+            ],
+        }
+    }
+
+    /// Read the key bindings file at the given path, which replaces the keys
+    /// of the commands it lists and keeps the defaults for the rest. There
+    /// being no file is fine.
+    ///
+    /// Each line of the file binds a command to keys separated by spaces,
+    /// like `move_n = Up NumPad8 k`, and lines starting with `#` are comments.
+    ///
+    /// Returns every problem with the file if there are any, including keys
+    /// bound to more than one command.
+    pub fn load(path: &Path) -> Result<KeyMap, Vec<String>> {
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {
+                return Ok(KeyMap::defaults());
+            },
+            Err(error) => return Err(vec![error.to_string()]),
+        };
+
+        let mut keymap = KeyMap::defaults();
+        let mut problems = vec![];
+        for (index, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, keys) = match line.split_once('=') {
+                Some((name, keys)) => (name.trim(), keys),
+                None => {
+                    problems.push(format!("line {}: missing `=`", index + 1));
+                    continue;
+                },
+            };
+            let binding = keymap
+                .bindings
+                .iter_mut()
+                .find(|(command, _)| command.name() == name);
+            let bound_keys = match binding {
+                Some((_, bound_keys)) => bound_keys,
+                None => {
+                    problems.push(format!(
+                        "line {}: unknown command `{}`",
+                        index + 1,
+                        name
+                    ));
+                    continue;
+                },
+            };
+            bound_keys.clear();
+            for key_name in keys.split_whitespace() {
+                match BoundKey::parse(key_name) {
+                    Some(key) => bound_keys.push(key),
+                    None => problems.push(format!(
+                        "line {}: unknown key `{}`",
+                        index + 1,
+                        key_name
+                    )),
+                }
+            }
+        }
+
+        problems.extend(keymap.conflicts());
+        if problems.is_empty() {
+            Ok(keymap)
+        } else {
+            Err(problems)
+        }
+    }
+
+    /// Every key that is bound to more than one command
+    fn conflicts(&self) -> Vec<String> {
+        let mut conflicts = vec![];
+        for (index, (command, keys)) in self.bindings.iter().enumerate() {
+            for key in keys {
+                let other = self.bindings[index + 1..]
+                    .iter()
+                    .find(|(_, other_keys)| other_keys.contains(key));
+                if let Some((other_command, _)) = other {
+                    conflicts.push(format!(
+                        "`{}` is bound to both {} and {}",
+                        key,
+                        command.name(),
+                        other_command.name()
+                    ));
+                }
+            }
+        }
+        conflicts
+    }
+
+    /// The command bound to the key, if any
+    pub fn command(&self, key: Key) -> Option<Command> {
+        self.bindings
+            .iter()
+            .find(|(_, keys)| keys.iter().any(|bound| bound.matches(key)))
+            .map(|&(command, _)| command)
+    }
+
+    pub fn keys(&self, command: Command) -> &[BoundKey] {
+        self.bindings
+            .iter()
+            .find(|&&(bound_command, _)| bound_command == command)
+            .map_or(&[], |(_, keys)| keys)
+    }
+}
+
+/// Combat-related properties and methods (monster, player, NPC, etc)
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Fighter {
+    max_hp: i32,
+    hp: i32,
+    defence: i32,
+    power: i32,
+    /// Experience: the running total for the player, and the reward for
+    /// killing it for monsters
+    xp: i32,
+    on_death: DeathCallback,
+}
+
+impl Fighter {
+    /// Heal by the given amount, without going over the given maximum (which
+    /// can be higher than `max_hp` thanks to equipment)
+    pub fn heal(&mut self, amount: i32, max_hp: i32) {
+        self.hp = cmp::min(self.hp + amount, max_hp);
+    }
+}
+
+/// The kinds of monsters that can be spawned
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum MonsterKind {
+    Orc,
+    Troll,
+}
+
+/// Monster Artificial Intelligence
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+enum Ai {
+    Basic,
+    /// Stumbles around randomly for a number of turns, then goes back to its
+    /// previous AI
+    Confused {
+        previous_ai: Box<Ai>,
+        num_turns: i32,
+    },
+}
+
+/// The kind of an item, which decides what happens when it is used
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Item {
+    Heal,
+    Lightning,
+    Confuse,
+    Fireball,
+    Sword,
+    Shield,
+    Armour,
+}
+
+impl Item {
+    /// What the player has to aim the item at before using it, if anything
+    fn target_kind(self) -> Option<TargetKind> {
+        use Item::*;
+        match self {
+            Heal | Lightning | Sword | Shield | Armour => None,
+            Confuse => Some(TargetKind::Monster {
+                max_range: Some(CONFUSE_RANGE as f32),
+            }),
+            Fireball => Some(TargetKind::Tile { max_range: None }),
+        }
+    }
+
+    /// What the item does, for look mode
+    fn description(self) -> String {
+        use Item::*;
+        match self {
+            Heal => format!("Heals {} hit points.", HEAL_AMOUNT),
+            Lightning => format!(
+                "Strikes the nearest enemy within {} tiles for {} damage.",
+                LIGHTNING_RANGE,
+                LIGHTNING_DAMAGE,
+            ),
+            Confuse => format!(
+                "Confuses an enemy within {} tiles for {} turns.",
+                CONFUSE_RANGE,
+                CONFUSE_NUM_TURNS,
+            ),
+            Fireball => format!(
+                "Burns everything within {} tiles of a target for {} damage.",
+                FIREBALL_RADIUS,
+                FIREBALL_DAMAGE,
+            ),
+            Sword | Shield | Armour => "Can be equipped.".to_string(),
+        }
+    }
+}
+
+/// The kind of target to pick in targeting mode, and how far from the player
+/// it can be
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TargetKind {
+    Tile { max_range: Option<f32> },
+    Monster { max_range: Option<f32> },
+}
+
+/// A target picked in targeting mode
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    Tile(i32, i32),
+    Monster(usize),
+}
+
+/// What happened when an item was used, so that it's only consumed on success
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum UseResult {
+    UsedUp,
+    /// Used, but not consumed (like equipment)
+    UsedAndKept,
+    Cancelled,
+}
+
+/// An object that can be equipped, yielding bonuses
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+struct Equipment {
+    slot: Slot,
+    equipped: bool,
+    power_bonus: i32,
+    defence_bonus: i32,
+    max_hp_bonus: i32,
+}
+
+/// Where a piece of equipment is worn; only one can be equipped per slot
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum Slot {
+    RightHand,
+    LeftHand,
+    Body,
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Slot::RightHand => write!(f, "right hand"),
+            Slot::LeftHand => write!(f, "left hand"),
+            Slot::Body => write!(f, "body"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum DeathCallback {
+    Player,
+    Monster,
+}
+
+impl DeathCallback {
+    /// Returns the experience granted to whoever caused the death
+    fn callback(self, object: &mut Object, game: &mut Game) -> i32 {
+        use DeathCallback::*;
+        let callback = match self {
+            Player => player_death,
+            Monster => monster_death,
+        };
+        callback(object, game)
+    }
+}
+
+/// What a message is about, so that the message log can show only one kind
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum MessageCategory {
+    Combat,
+    Item,
+    System,
+    Lore,
+}
+
+impl MessageCategory {
+    const ALL: [MessageCategory; 4] = [
+        MessageCategory::Combat,
+        MessageCategory::Item,
+        MessageCategory::System,
+        MessageCategory::Lore,
+    ];
+}
+
+#[derive(Serialize, Deserialize)]
+struct Message {
+    text: String,
+    colour: Color,
+    category: MessageCategory,
+    /// The turn the message was first added on
+    turn: u32,
+    /// How many times in a row the message was added
+    count: u32,
+}
+
+impl Message {
+    /// The text, followed by how many times it was repeated, if it was
+    pub fn full_text(&self) -> String {
+        if self.count > 1 {
+            format!("{} (x{})", self.text, self.count)
+        } else {
+            self.text.clone()
+        }
+    }
+}
+
+/// The most recent messages, up to `capacity` of them
+#[derive(Serialize, Deserialize)]
+struct Messages {
+    messages: VecDeque<Message>,
+    /// How many messages are kept
+    capacity: usize,
+    /// The turn that new messages are added on
+    turn: u32,
+}
+
+impl Messages {
+    pub fn new() -> Self {
+        Self::with_capacity(MAX_MESSAGES)
+    }
+
+    /// Keep only the given number of the most recent messages
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            messages: VecDeque::new(),
+            capacity,
+            turn: 1,
+        }
+    }
+
+    /// Add the new message, with its colour and category, on the current
+    /// turn. The same message as the last one is counted on that one instead,
+    /// and the oldest message is forgotten once there are too many.
+    pub fn add<T: Into<String>>(
+        &mut self,
+        message: T,
+        colour: Color,
+        category: MessageCategory,
+    ) {
+        let text = message.into();
+        if let Some(last) = self.messages.back_mut() {
+            if last.text == text
+                && last.colour == colour
+                && last.category == category
+            {
+                last.count += 1;
+                return;
+            }
+        }
+
+        self.messages.push_back(Message {
+            text,
+            colour,
+            category,
+            turn: self.turn,
+            count: 1,
+        });
+        while self.messages.len() > self.capacity {
+            self.messages.pop_front();
+        }
+    }
+
+    /// Add the messages that follow on the given turn
+    pub fn set_turn(&mut self, turn: u32) {
+        self.turn = turn;
+    }
+
+    /// Create a `DoubleEndedIterator` over the messages
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &Message> {
+        self.messages.iter()
+    }
+}
+
+/// What gets written to the save file
+#[derive(Serialize)]
+struct SaveFileRef<'a> {
+    version: u32,
+    game: &'a Game,
+    objects: &'a [Object],
+}
+
+/// What gets read back from the save file
+#[derive(Deserialize)]
+struct SaveFile {
+    game: Game,
+    objects: Vec<Object>,
+}
+
+/// Why a saved game couldn't be loaded
+#[derive(Debug)]
+enum LoadError {
+    NotFound,
+    Io(io::Error),
+    Corrupt(serde_json::Error),
+    /// The file was written by another version of the game (or has no version
+    /// at all)
+    IncompatibleVersion(Option<u64>),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use LoadError::*;
+        match self {
+            NotFound => write!(f, "there is no saved game to load"),
+            Io(error) => {
+                write!(f, "the saved game could not be read ({})", error)
+            },
+            Corrupt(error) => {
+                write!(f, "the saved game is corrupt ({})", error)
+            },
+            IncompatibleVersion(Some(version)) => write!(
+                f,
+                "the saved game is from an incompatible version (save format \
+                {}, expected {})",
+                version,
+                SAVE_VERSION
+            ),
+            IncompatibleVersion(None) => write!(
+                f,
+                "the saved game is from an incompatible version (no save \
+                format, expected {})",
+                SAVE_VERSION
+            ),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+fn main() {
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        },
+    };
+
+    // A headless game plays its script without ever opening a window
+    if let Some(script_path) = args.headless_script {
+        if let Err(error) = run_headless(&script_path, args.seed) {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+        return;
+    }
+
+    let root = Root::initializer()
+        .font("arial10x10.png", FontLayout::Tcod)
+        .font_type(FontType::Greyscale)
+        .size(SCREEN_WIDTH, SCREEN_HEIGHT)
+        .title("RustyRogue")
+        .init();
+
+    let mut tcod = Tcod {
+        root,
+        con: Offscreen::new(MAP_WIDTH, MAP_HEIGHT),
+        panel: Offscreen::new(SCREEN_WIDTH, PANEL_HEIGHT),
+        fov: FovMap::new(MAP_WIDTH, MAP_HEIGHT),
+        key: Default::default(),
+        mouse: Default::default(),
+        keymap: KeyMap::defaults(),
+    };
+
+    tcod::system::set_fps(LIMIT_FPS);
+
+    if let Some(path) = key_bindings_path() {
+        match KeyMap::load(&path) {
+            Ok(keymap) => tcod.keymap = keymap,
+            Err(problems) => {
+                render_title(&mut tcod.root);
+                msgbox(
+                    &format!(
+                        "\nThere are problems with the key bindings in {}:\n\n\
+                        {}\n\nUsing the default key bindings instead.\n",
+                        path.display(),
+                        problems.join("\n"),
+                    ),
+                    KEY_BINDINGS_WIDTH,
+                    &mut tcod.root,
+                );
+            },
+        }
+    }
+
+    main_menu(&mut tcod, args.seed);
+}
+
+/// Where the key bindings file is: `rusty_rogue/keys.cfg` in the user's
+/// config directory, if they have one
+fn key_bindings_path() -> Option<PathBuf> {
+    let config_dir = env::var_os("XDG_CONFIG_HOME")
+        .or_else(|| env::var_os("APPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| {
+            env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+        })?;
+    Some(config_dir.join("rusty_rogue").join("keys.cfg"))
+}
+
+/// List every command and the keys bound to it
+fn key_bindings_screen(tcod: &mut Tcod) {
+    let mut text = String::from("Key bindings\n\n");
+    for command in Command::ALL {
+        let keys: Vec<String> = tcod
+            .keymap
+            .keys(command)
+            .iter()
+            .map(|key| key.to_string())
+            .collect();
+        text.push_str(&format!("{:<14}{}\n", command.name(), keys.join(" ")));
+    }
+    text.push_str("\nEscape         main menu\nAlt+Enter      fullscreen\n");
+    if let Some(path) = key_bindings_path() {
+        text.push_str(&format!("\nChange them in {}\n", path.display()));
+    }
+    msgbox(&text, KEY_BINDINGS_WIDTH, &mut tcod.root);
+}
+
+/// The command-line options
+struct Args {
+    /// `--seed <number>`: the seed for new games, instead of a random one
+    seed: Option<u32>,
+    /// `--headless <script>`: play the script's actions without a window
+    headless_script: Option<String>,
+}
+
+fn parse_args() -> Result<Args, String> {
+    let mut parsed = Args {
+        seed: None,
+        headless_script: None,
+    };
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--seed" => {
+                let value = args.next().ok_or("Missing a value for --seed")?;
+                let seed = value
+                    .parse()
+                    .map_err(|_| format!("Invalid seed: {}", value))?;
+                parsed.seed = Some(seed);
+            },
+            "--headless" => {
+                let script_path = args
+                    .next()
+                    .ok_or("Missing a script for --headless")?;
+                parsed.headless_script = Some(script_path);
+            },
+            _ => return Err(format!("Unknown argument: {}", arg)),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Play a new game without a window, taking the player's actions from a
+/// script, then print its messages and how it ended.
+///
+/// The script has one action per line (see `PlayerAction::from_str`), and
+/// blank lines and `#` comments are skipped. It stops early if the player
+/// dies.
+fn run_headless(
+    script_path: &str,
+    seed: Option<u32>,
+) -> Result<(), Box<dyn Error>> {
+    let script = fs::read_to_string(script_path)?;
+    let seed = seed.unwrap_or_else(rand::random);
+    let mut simulation = Simulation::new(seed);
+
+    let mut turns = 0;
+    for (index, line) in script.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        let action = line.parse::<PlayerAction>().map_err(|error| {
+            format!("{}:{}: {}", script_path, index + 1, error)
+        })?;
+
+        // Travelling and exploring keep going, as they do in the game
+        loop {
+            let result = simulation.step(action);
+            if result == TurnResult::TookTurn {
+                turns += 1;
+            }
+            if result == TurnResult::DidntTakeTurn || !action.repeats() {
+                break;
+            }
+        }
+        if !simulation.objects[PLAYER].alive {
+            break;
+        }
+    }
+
+    for message in simulation.game.messages.iter() {
+        println!("{}", message.full_text());
+    }
+
+    let game = &simulation.game;
+    let player = &simulation.objects[PLAYER];
+    let hp = player.fighter.map_or(0, |fighter| fighter.hp);
+    println!();
+    println!("Seed: {}", seed);
+    println!("Turns taken: {}", turns);
+    println!("Dungeon level: {}", game.dungeon_level);
+    println!(
+        "Player: {} at ({}, {}), level {}, HP {}/{}",
+        if player.alive { "alive" } else { "dead" },
+        player.x,
+        player.y,
+        player.level,
+        hp,
+        player.max_hp(game),
+    );
+    Ok(())
+}
+
+/// The title screen, which is where every game starts and ends.
+///
+/// New games use the given seed, or a random one if there is none.
+fn main_menu(tcod: &mut Tcod, seed: Option<u32>) {
+    while !tcod.root.window_closed() {
+        render_title(&mut tcod.root);
+
+        // Show the options and wait for the player's choice
+        let choices = &["New Game", "Continue", "Options", "Quit"];
+        let choice = menu("", choices, MAIN_MENU_WIDTH, &mut tcod.root);
+
+        match choice {
+            Some(0) => {
+                // New game
+                let seed = seed.unwrap_or_else(rand::random);
+                println!("Starting a new game with seed {}", seed);
+                let (mut game, mut objects) = new_game(seed);
+                initialise_fov(&mut tcod.fov, &game.map);
+                compute_fov(&mut tcod.fov, &mut game.map, &objects);
+                play_game(tcod, &mut game, &mut objects);
+            },
+            Some(1) => {
+                // Load game
+                match load_game() {
+                    Ok((mut game, mut objects)) => {
+                        initialise_fov(&mut tcod.fov, &game.map);
+                        compute_fov(
+                            &mut tcod.fov,
+                            &mut game.map,
+                            &objects,
+                        );
+                        play_game(tcod, &mut game, &mut objects);
+                    },
+                    Err(error) => {
+                        msgbox(
+                            &format!("\nCannot continue: {}.\n", error),
+                            MAIN_MENU_WIDTH,
+                            &mut tcod.root,
+                        );
+                    },
+                }
+            },
+            Some(2) => options_menu(tcod),
+            Some(3) => break,
+            _ => {},
+        }
+    }
+}
+
+fn options_menu(tcod: &mut Tcod) {
+    loop {
+        render_title(&mut tcod.root);
+
+        let fullscreen = tcod.root.is_fullscreen();
+        let choices = &[
+            format!("Fullscreen: {}", if fullscreen { "on" } else { "off" }),
+            "Key bindings".to_string(),
+            "Back".to_string(),
+        ];
+        match menu("Options\n", choices, MAIN_MENU_WIDTH, &mut tcod.root) {
+            Some(0) => tcod.root.set_fullscreen(!fullscreen),
+            Some(1) => {
+                render_title(&mut tcod.root);
+                key_bindings_screen(tcod);
+            },
+            _ => break,
+        }
+    }
+}
+
+/// Clear the screen and show the game's title, as a background for the main
+/// menu
+fn render_title(root: &mut Root) {
+    root.set_default_background(BLACK);
+    root.clear();
+    root.set_default_foreground(LIGHT_YELLOW);
+    root.print_ex(
+        SCREEN_WIDTH / 2,
+        SCREEN_HEIGHT / 2 - 6,
+        BackgroundFlag::None,
+        TextAlignment::Center,
+        "RUSTY ROGUE",
+    );
+    root.print_ex(
+        SCREEN_WIDTH / 2,
+        SCREEN_HEIGHT / 2 - 4,
+        BackgroundFlag::None,
+        TextAlignment::Center,
+        "The Tombs of the Ancient Kings",
+    );
+}
+
+fn new_game(seed: u32) -> (Game, Vec<Object>) {
+    let centre_x = SCREEN_WIDTH / 2;
+    let centre_y = SCREEN_HEIGHT / 2;
+
+    // Create the object representing the player
+    let mut player = Object::new(
+        0,
+        0,
+        '@',
+        WHITE,
+        "Player",
+        true,
+    );
+    player.alive = true;
+    player.fighter = Some(Fighter {
+        max_hp: 30,
+        hp: 30,
+        defence: 2,
+        power: 5,
+        xp: 0,
+        on_death: DeathCallback::Player,
+    });
+
+    // Create an NPC
+    let mut npc = Object::new(
+        centre_x - 5,
+        centre_y,
+        '@',
+        YELLOW,
+        "NPC",
+        true,
+    );
+    npc.alive = true;
+
+    // The list of objects with those two
+    let mut objects = vec![player, npc];
+
+    // Generate map (at this point it's not drawn on the screen)
+    let mut rng = GameRng::new(seed);
+    let map = make_map(&mut objects, 1, &mut rng);
+
+    let mut game = Game {
+        map,
+        messages: Messages::new(),
+        inventory: vec![],
+        dungeon_level: 1,
+        turn: 1,
+        kills: 0,
+        rng,
+    };
+
+    game.messages.add(
         "Welcome, stranger! Prepare to perish in the Tombs of the
         Ancient Kings…",
         RED,
+        MessageCategory::Lore,
+    );
+
+    (game, objects)
+}
+
+/// Populate the FOV map, according to the generated (or loaded) map
+fn initialise_fov(fov: &mut FovMap, map: &Map) {
+    for y in 0..MAP_HEIGHT {
+        for x in 0..MAP_WIDTH {
+            fov.set(
+                x,
+                y,
+                !map[x as usize][y as usize].block_sight,
+                !map[x as usize][y as usize].blocked,
+            );
+        }
+    }
+}
+
+/// Recompute what the player can see from where they are, and remember every
+/// tile they have seen as explored
+fn compute_fov(fov: &mut FovMap, map: &mut Map, objects: &[Object]) {
+    let player = &objects[PLAYER];
+    fov.compute_fov(
+        player.x,
+        player.y,
+        TORCH_RADIUS,
+        FOV_LIGHT_WALLS,
+        FOV_ALGO
     );
 
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            if fov.is_in_fov(x, y) {
+                map[x as usize][y as usize].explored = true;
+            }
+        }
+    }
+}
+
+/// Play one turn of the game: carry out the player's action and, if it used
+/// up their turn, let the monsters take theirs.
+///
+/// This is all of the game's rules, with no rendering or input, so the same
+/// turns are played with a window or headless.
+fn play_turn(
+    action: PlayerAction,
+    fov: &mut FovMap,
+    game: &mut Game,
+    objects: &mut Vec<Object>,
+) -> TurnResult {
+    use PlayerAction::*;
+    use TurnResult::*;
+
+    // The dead don't act
+    if !objects[PLAYER].alive {
+        return DidntTakeTurn;
+    }
+
+    let result = match action {
+        MoveOrAttack(dx, dy) => {
+            player_move_or_attack(dx, dy, game, objects);
+            TookTurn
+        },
+        Wait => TookTurn,
+        PickUp => {
+            let item_id = objects.iter().position(|object| {
+                object.pos() == objects[PLAYER].pos() && object.item.is_some()
+            });
+            if let Some(item_id) = item_id {
+                pick_item_up(item_id, game, objects);
+            }
+            DidntTakeTurn
+        },
+        UseItem(inventory_id, target) => {
+            if inventory_id >= game.inventory.len() {
+                return DidntTakeTurn;
+            }
+            match use_item(inventory_id, target, fov, game, objects) {
+                UseResult::UsedUp | UseResult::UsedAndKept => TookTurn,
+                UseResult::Cancelled => DidntTakeTurn,
+            }
+        },
+        DropItem(inventory_id) => {
+            if inventory_id < game.inventory.len() {
+                drop_item(inventory_id, game, objects);
+            }
+            DidntTakeTurn
+        },
+        Descend => {
+            let player_on_stairs = objects.iter().any(|object| {
+                object.pos() == objects[PLAYER].pos()
+                    && object.name == "stairs"
+            });
+            if player_on_stairs {
+                next_level(fov, game, objects);
+            }
+            DidntTakeTurn
+        },
+        LevelUp(stat) => {
+            if can_level_up(&objects[PLAYER]) {
+                level_up(stat, game, objects);
+            }
+            DidntTakeTurn
+        },
+        Travel(x, y) => {
+            let on_map = (0..MAP_WIDTH).contains(&x)
+                && (0..MAP_HEIGHT).contains(&y);
+            if on_map && game.map[x as usize][y as usize].explored {
+                player_step_downhill(&[((x, y), 0)], fov, game, objects)
+            } else {
+                game.messages.add(
+                    "You don't know the way there.",
+                    WHITE,
+                    MessageCategory::System,
+                );
+                DidntTakeTurn
+            }
+        },
+        Explore => {
+            let mut unexplored = vec![];
+            for x in 0..MAP_WIDTH {
+                for y in 0..MAP_HEIGHT {
+                    let tile = &game.map[x as usize][y as usize];
+                    if !tile.explored && !tile.blocked {
+                        unexplored.push(((x, y), 0));
+                    }
+                }
+            }
+            let result = player_step_downhill(&unexplored, fov, game, objects);
+            if result == DidntTakeTurn && !enemies_in_view(fov, objects) {
+                game.messages.add(
+                    "There is nothing left to explore.",
+                    WHITE,
+                    MessageCategory::System,
+                );
+            }
+            result
+        },
+    };
+
+    // The player may have moved, so the monsters act on what they see now
+    compute_fov(fov, &mut game.map, objects);
+
+    // Let monsters take their turn
+    if objects[PLAYER].alive && result == TookTurn {
+        let monster_maps = MonsterMaps::new(&game.map, objects);
+        for id in 0..objects.len() {
+            if objects[id].ai.is_some() {
+                ai_take_turn(id, fov, &monster_maps, game, objects);
+            }
+        }
+    }
+
+    if result == TookTurn {
+        game.turn += 1;
+        game.messages.set_turn(game.turn);
+    }
+
+    result
+}
+
+/// Move the player a step towards the nearest of the targets, only going
+/// through explored tiles, unless there are enemies in view
+fn player_step_downhill(
+    targets: &[((i32, i32), i32)],
+    fov: &FovMap,
+    game: &mut Game,
+    objects: &mut [Object],
+) -> TurnResult {
+    if enemies_in_view(fov, objects) {
+        game.messages.add(
+            "Not with enemies in view!",
+            WHITE,
+            MessageCategory::System,
+        );
+        return TurnResult::DidntTakeTurn;
+    }
+
+    let map = &game.map;
+    let targets = DijkstraMap::new(targets, |x, y| {
+        let tile = &map[x as usize][y as usize];
+        tile.explored && !tile.blocked
+    });
+    let (x, y) = objects[PLAYER].pos();
+    match targets.downhill(x, y, map, objects) {
+        Some((next_x, next_y)) => {
+            objects[PLAYER].set_pos(next_x, next_y);
+            TurnResult::TookTurn
+        },
+        None => TurnResult::DidntTakeTurn,
+    }
+}
+
+/// Whether the player can see any monsters
+fn enemies_in_view(fov: &FovMap, objects: &[Object]) -> bool {
+    objects.iter().any(|object| {
+        object.ai.is_some()
+            && object.fighter.is_some()
+            && fov.is_in_fov(object.x, object.y)
+    })
+}
+
+fn play_game(tcod: &mut Tcod, game: &mut Game, objects: &mut Vec<Object>) {
+    let mut state = if objects[PLAYER].alive {
+        GameState::Playing
+    } else {
+        GameState::Dead
+    };
+
     // The main game loop
-    while !tcod.root.window_closed() {
+    while !tcod.root.window_closed() && state != GameState::MainMenu {
         // Clear the screen of the previous frame
         tcod.con.clear();
 
-        // Render the screen
-        let fov_recompute = previous_player_position != (
-            objects[PLAYER].x,
-            objects[PLAYER].y
-        );
-
+        // Clicks only count on the frame they happen
+        tcod.mouse.lbutton_pressed = false;
+        tcod.mouse.rbutton_pressed = false;
         match input::check_for_event(
             input::MOUSE | input::KEY_PRESS
         ) {
@@ -399,181 +2510,572 @@ fn main() {
             _ => tcod.key = Default::default(),
         }
 
-        render_all(&mut tcod, &mut game, &objects, fov_recompute);
+        // Render the screen
+        render_all(tcod, game, objects);
 
         tcod.root.flush();
 
-        // Handle keys and exit game if needed
-        previous_player_position = objects[PLAYER].pos();
-        let player_action = handle_keys(
-            &mut tcod,
-            &mut game,
-            &mut objects
-        );
-        if player_action == PlayerAction::Exit {
-            break;
+        // Let the current state handle the frame, turning the player's input
+        // into an action
+        let (next_state, player_action) = match state {
+            GameState::Playing | GameState::Dead => {
+                handle_keys(tcod, game, objects)
+            },
+            GameState::Repeating(action) => {
+                // Any key stops it
+                if tcod.key.code == input::KeyCode::NoKey {
+                    (state, Some(action))
+                } else {
+                    (GameState::Playing, None)
+                }
+            },
+            GameState::InventoryOpen(action) => {
+                handle_inventory(action, tcod, game)
+            },
+            GameState::Targeting(inventory_id) => {
+                handle_targeting(inventory_id, tcod, game, objects)
+            },
+            GameState::MainMenu => unreachable!(),
+        };
+        state = next_state;
+
+        if let Some(player_action) = player_action {
+            let result = play_turn(player_action, &mut tcod.fov, game, objects);
+            if player_action.repeats() {
+                // Keep going for as long as it takes turns
+                state = if result == TurnResult::TookTurn {
+                    GameState::Repeating(player_action)
+                } else {
+                    GameState::Playing
+                };
+            }
         }
 
-        // Let monsters take their turn
-        if objects[PLAYER].alive
-            && player_action != PlayerAction::DidntTakeTurn {
-            for id in 0..objects.len() {
-                if objects[id].ai.is_some() {
-                    ai_take_turn(
-                        id,
-                        &tcod,
-                        &mut game,
-                        &mut objects,
-                    );
-                }
+        // Level up as soon as the player has the experience for it
+        if objects[PLAYER].alive && can_level_up(&objects[PLAYER]) {
+            if let Some(stat) = level_up_menu(tcod, &objects[PLAYER]) {
+                let action = PlayerAction::LevelUp(stat);
+                play_turn(action, &mut tcod.fov, game, objects);
             }
         }
+
+        if !objects[PLAYER].alive && state != GameState::MainMenu {
+            state = GameState::Dead;
+        }
+    }
+
+    if objects[PLAYER].alive {
+        // Autosave, so that the game can be continued from the main menu
+        if let Err(error) = save_game(game, objects) {
+            msgbox(
+                &format!("\nCould not save the game: {}.\n", error),
+                MAIN_MENU_WIDTH,
+                &mut tcod.root,
+            );
+        }
+    } else {
+        // There's nothing to continue once the player is dead, so a previous
+        // save must not bring them back to life
+        let _ = fs::remove_file(SAVE_FILE);
+    }
+}
+
+/// Write the whole game state to the save file, replacing any previous save
+fn save_game(game: &Game, objects: &[Object]) -> Result<(), Box<dyn Error>> {
+    let save_data = to_save_data(game, objects)?;
+    let mut file = File::create(SAVE_FILE)?;
+    file.write_all(save_data.as_bytes())?;
+    Ok(())
+}
+
+/// Read the game state back from the save file, refusing files written by an
+/// incompatible version of the game
+fn load_game() -> Result<(Game, Vec<Object>), LoadError> {
+    let mut json_save_state = String::new();
+    let mut file = File::open(SAVE_FILE).map_err(|error| {
+        if error.kind() == io::ErrorKind::NotFound {
+            LoadError::NotFound
+        } else {
+            LoadError::Io(error)
+        }
+    })?;
+    file.read_to_string(&mut json_save_state).map_err(LoadError::Io)?;
+    from_save_data(&json_save_state)
+}
+
+/// Write the game state in the save file's format
+fn to_save_data(
+    game: &Game,
+    objects: &[Object],
+) -> Result<String, serde_json::Error> {
+    serde_json::to_string(&SaveFileRef {
+        version: SAVE_VERSION,
+        game,
+        objects,
+    })
+}
+
+/// Read the game state back from the save file's format, refusing data
+/// written by an incompatible version of the game
+fn from_save_data(
+    json_save_state: &str,
+) -> Result<(Game, Vec<Object>), LoadError> {
+    // Check the version first, so that an old save file is reported as such
+    // instead of as whichever field failed to parse
+    let save_data = serde_json::from_str::<serde_json::Value>(json_save_state)
+        .map_err(LoadError::Corrupt)?;
+    let version = save_data
+        .get("version")
+        .and_then(|version| version.as_u64());
+    if version != Some(SAVE_VERSION as u64) {
+        return Err(LoadError::IncompatibleVersion(version));
     }
+
+    let save_file = serde_json::from_value::<SaveFile>(save_data)
+        .map_err(LoadError::Corrupt)?;
+    Ok((save_file.game, save_file.objects))
 }
 
 fn handle_keys(
     tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &mut [Object],
-) -> PlayerAction {
-    use tcod::input::KeyCode::*;
+    game: &Game,
+    objects: &[Object],
+) -> (GameState, Option<PlayerAction>) {
+    use GameState::*;
     use PlayerAction::*;
 
     let player_alive = objects[PLAYER].alive;
-    return match (tcod.key, tcod.key.text(), player_alive) {
+    let state = if player_alive { Playing } else { Dead };
+
+    match tcod.key {
         // Alt+Enter: Toggle Fullscreen
-        (
-            Key {
-                code: Enter,
-                alt: true,
-                ..
-            },
-            _,
-            _
-        ) => {
+        Key {
+            code: KeyCode::Enter,
+            alt: true,
+            ..
+        } => {
             let fullscreen = tcod.root.is_fullscreen();
             tcod.root.set_fullscreen(!fullscreen);
-            DidntTakeTurn
+            return (state, None);
         },
-        // Exit game
-        (Key { code: Escape, .. }, _, _) => Exit,
+        // Go back to the main menu
+        Key { code: KeyCode::Escape, .. } => return (MainMenu, None),
+        _ => {},
+    }
 
-        // Movement keys
-        (Key { code: Up, .. }, _, _) => {
-            player_move_or_attack(0, -1, game, objects);
-            TookTurn
+    // Travel to the tile that was clicked
+    if player_alive && tcod.mouse.lbutton_pressed {
+        let (x, y) = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+        let on_map = (0..MAP_WIDTH).contains(&x)
+            && (0..MAP_HEIGHT).contains(&y);
+        if on_map {
+            return (state, Some(Travel(x, y)));
+        }
+    }
+
+    let command = match tcod.keymap.command(tcod.key) {
+        Some(command) => command,
+        None => return (state, None),
+    };
+    // These screens are also there for the dead
+    match command {
+        Command::KeyBindings => {
+            key_bindings_screen(tcod);
+            return (state, None);
         },
-        (Key { code: Down, .. }, _, _) => {
-            player_move_or_attack(0, 1, game, objects);
-            TookTurn
+        Command::MessageLog => {
+            message_log(tcod, game);
+            return (state, None);
         },
-        (Key { code: Left, .. }, _, _) => {
-            player_move_or_attack(-1, 0, game, objects);
-            TookTurn
+        Command::Look => {
+            look_mode(tcod, game, objects);
+            return (state, None);
         },
-        (Key { code: Right, .. }, _, _) => {
-            player_move_or_attack(1, 0, game, objects);
-            TookTurn
+        Command::CharacterSheet => {
+            character_sheet(tcod, game, &objects[PLAYER]);
+            return (state, None);
+        },
+        _ => {},
+    }
+    if !player_alive {
+        return (state, None);
+    }
+
+    // Movement keys, which also attack
+    if let Some((dx, dy)) = command.direction() {
+        return (state, Some(MoveOrAttack(dx, dy)));
+    }
+
+    match command {
+        Command::Wait => (state, Some(Wait)),
+        Command::PickUp => (state, Some(PickUp)),
+        Command::Inventory => (InventoryOpen(InventoryAction::Use), None),
+        Command::Drop => (InventoryOpen(InventoryAction::Drop), None),
+
+        // Go down the stairs if the player is on them, or travel to them if
+        // they have been seen
+        Command::Descend => {
+            let stairs = objects
+                .iter()
+                .find(|object| object.name == "stairs")
+                .filter(|stairs| {
+                    game.map[stairs.x as usize][stairs.y as usize].explored
+                });
+            match stairs {
+                Some(stairs) if stairs.pos() != objects[PLAYER].pos() => {
+                    (state, Some(Travel(stairs.x, stairs.y)))
+                },
+                _ => (state, Some(Descend)),
+            }
         },
-        
-        _ => DidntTakeTurn,
+
+        // Explore until something comes into view
+        Command::Explore => (state, Some(Explore)),
+
+        _ => (state, None),
     }
 }
 
-fn make_map(objects: &mut Vec<Object>) -> Map {
-    // Fill map with "blocked" tiles
-    let mut map = vec![
-        vec![Tile::wall(); MAP_HEIGHT as usize];
-        MAP_WIDTH as usize
-    ];
+/// Show the inventory menu on top of the game, and use or drop the chosen item
+fn handle_inventory(
+    action: InventoryAction,
+    tcod: &mut Tcod,
+    game: &Game,
+) -> (GameState, Option<PlayerAction>) {
+    let header = match action {
+        InventoryAction::Use => {
+            "Press the key next to an item to use it, or any other to \
+            cancel.\n"
+        },
+        InventoryAction::Drop => {
+            "Press the key next to an item to drop it, or any other to \
+            cancel.\n"
+        },
+    };
+    let inventory_index = match inventory_menu(
+        &game.inventory,
+        header,
+        &mut tcod.root
+    ) {
+        Some(inventory_index) => inventory_index,
+        None => return (GameState::Playing, None),
+    };
 
-    let mut rooms = Vec::new();
+    match action {
+        InventoryAction::Use => {
+            let target_kind = game.inventory[inventory_index]
+                .item
+                .and_then(|item| item.target_kind());
+            if target_kind.is_some() {
+                // Pick a target first, then use the item on it
+                (GameState::Targeting(inventory_index), None)
+            } else {
+                let action = PlayerAction::UseItem(inventory_index, None);
+                (GameState::Playing, Some(action))
+            }
+        },
+        InventoryAction::Drop => {
+            let action = PlayerAction::DropItem(inventory_index);
+            (GameState::Playing, Some(action))
+        },
+    }
+}
 
-    for _ in 0..MAX_ROOMS {
-        // Random width and height
-        let w = rand::thread_rng().gen_range(
-            ROOM_MIN_SIZE,
-            ROOM_MAX_SIZE + 1
-        );
-        let h = rand::thread_rng().gen_range(
-            ROOM_MIN_SIZE,
-            ROOM_MAX_SIZE + 1
-        );
-        // Random position without going out of the boundaries of the map
-        let x = rand::thread_rng().gen_range(0, MAP_WIDTH - w);
-        let y = rand::thread_rng().gen_range(0, MAP_HEIGHT - h);
+/// Let the player pick a target for the item in the given inventory slot, and
+/// use the item on it
+fn handle_targeting(
+    inventory_id: usize,
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &[Object],
+) -> (GameState, Option<PlayerAction>) {
+    let item = &game.inventory[inventory_id];
+    let target = match item.item.and_then(|item| item.target_kind()) {
+        Some(TargetKind::Tile { max_range }) => {
+            game.messages.add(
+                format!(
+                    "Left-click a target tile (or move to it and press Enter) \
+                    for the {}, or right-click (or press Escape) to cancel.",
+                    item.name
+                ),
+                LIGHT_CYAN,
+                MessageCategory::System,
+            );
+            target_tile(tcod, game, objects, max_range)
+                .map(|(x, y)| Target::Tile(x, y))
+        },
+        Some(TargetKind::Monster { max_range }) => {
+            game.messages.add(
+                format!(
+                    "Left-click an enemy (or move to it and press Enter) for \
+                    the {}, or right-click (or press Escape) to cancel.",
+                    item.name
+                ),
+                LIGHT_CYAN,
+                MessageCategory::System,
+            );
+            target_monster(tcod, game, objects, max_range)
+                .map(Target::Monster)
+        },
+        None => None,
+    };
 
-        let new_room = Rect::new(x, y, w, h);
+    match target {
+        Some(target) => {
+            let action = PlayerAction::UseItem(inventory_id, Some(target));
+            (GameState::Playing, Some(action))
+        },
+        None => {
+            game.messages.add("Cancelled", WHITE, MessageCategory::System);
+            (GameState::Playing, None)
+        },
+    }
+}
 
-        // Run through the other rooms and see if they intersect with this one
-        let failed = rooms
-            .iter()
-            .any(|other_room| new_room.intersects_with(other_room));
-        
-        if !failed {
-            // This means that there are no intersections, so this room is
-            // valid
+/// Generate the map for the given dungeon level, and put the player, the
+/// stairs, and the level's monsters and items on it
+fn make_map(objects: &mut Vec<Object>, level: u32, rng: &mut GameRng) -> Map {
+    // Generate levels until one is playable, which is nearly always the first
+    let mut generated = loop {
+        let generated = map_generator(level, rng).generate(rng);
+        if is_playable(&generated) {
+            break generated;
+        }
+    };
 
-            // "Paint" it to the map's tiles
-            create_room(new_room, &mut map);
+    let (start_x, start_y) = generated.player_start;
+    objects[PLAYER].set_pos(start_x, start_y);
 
-            // Add some content to this room, such as monsters
-            place_objects(new_room, objects, &map);
+    place_vaults(&mut generated.map, objects, level, rng);
 
-            // Centre coordinates of the new room, will be useful later
-            let (new_x, new_y) = new_room.centre();
+    // Add some content to each area, such as monsters
+    for area in &generated.spawn_areas {
+        place_objects(area, objects, &generated.map, level, rng);
+    }
 
-            if rooms.is_empty() {
-                // This is the first room, where the player starts at
-                objects[PLAYER].set_pos(new_x, new_y);
-            } else {
-                // All rooms after the first:
-                // Connect it to the previous room with a tunnel
+    let (stairs_x, stairs_y) = generated.stairs;
+    let mut stairs = Object::new(
+        stairs_x,
+        stairs_y,
+        '>',
+        WHITE,
+        "stairs",
+        false
+    );
+    stairs.always_visible = true;
+    objects.push(stairs);
 
-                // Centre coordinates of the previous room
-                let (prev_x, prev_y) = rooms[rooms.len() - 1]
-                    .centre();
+    generated.map
+}
 
-                // Toss a coin (random bool value – either true or false)
-                if rand::random::<bool>() {
-                    // First move horizontally, then vertically
-                    create_h_tunnel(prev_x, new_x, prev_y, &mut map);
-                    create_v_tunnel(prev_y, new_y, new_x, &mut map);
-                } else {
-                    // First move vertically, then horizontally
-                    create_v_tunnel(prev_y, new_y, prev_x, &mut map);
-                    create_h_tunnel(prev_x, new_x, new_y, &mut map);
-                }
-            }
+/// Choose how the given dungeon level is generated. The dungeon starts out as
+/// built rooms, and turns more and more into natural caves and tunnels
+/// deeper down.
+fn map_generator(level: u32, rng: &mut GameRng) -> Box<dyn MapGenerator> {
+    let style_chances = &mut [
+        Weighted { weight: 40, item: LevelStyle::Rooms },
+        Weighted { weight: 40, item: LevelStyle::Bsp },
+        Weighted {
+            weight: from_dungeon_level(&[(20, 2), (30, 4)], level),
+            item: LevelStyle::Hybrid,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[(15, 3), (30, 5)], level),
+            item: LevelStyle::Caves,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[(10, 4), (25, 6)], level),
+            item: LevelStyle::Tunnels,
+        },
+    ];
+    let style = WeightedChoice::new(style_chances).ind_sample(rng);
 
-            rooms.push(new_room);
-        }
+    let rooms = RoomsAndCorridors {
+        max_rooms: MAX_ROOMS,
+        room_min_size: ROOM_MIN_SIZE,
+        room_max_size: ROOM_MAX_SIZE,
+    };
+    let bsp = Bsp {
+        min_leaf_size: BSP_MIN_LEAF_SIZE,
+        room_min_size: ROOM_MIN_SIZE,
+    };
+    let caves = Caves {
+        initial_wall_chance: CAVE_WALL_CHANCE,
+        smoothing_steps: CAVE_SMOOTHING_STEPS,
+    };
+    match style {
+        LevelStyle::Rooms => Box::new(rooms),
+        LevelStyle::Bsp => Box::new(bsp),
+        LevelStyle::Caves => Box::new(caves),
+        LevelStyle::Tunnels => Box::new(DrunkardsWalk {
+            floor_percent: DRUNKARD_FLOOR_PERCENT,
+            max_steps: DRUNKARD_MAX_STEPS,
+        }),
+        LevelStyle::Hybrid => Box::new(Hybrid { rooms: bsp, caves }),
     }
+}
 
-    map
+/// Whether the player can get from the start to the stairs, and the level has
+/// enough floor to be worth playing
+fn is_playable(generated: &GeneratedMap) -> bool {
+    let floor = generated
+        .map
+        .iter()
+        .flatten()
+        .filter(|tile| !tile.blocked)
+        .count() as i32;
+    let enough_floor =
+        floor * 100 >= MIN_FLOOR_PERCENT * MAP_WIDTH * MAP_HEIGHT;
+    let connected = flood_fill(&generated.map, generated.player_start)
+        .contains(&generated.stairs);
+    enough_floor && connected
 }
 
-fn render_all(
-    tcod: &mut Tcod,
-    game: &mut Game,
-    objects: &[Object],
-    fov_recompute: bool,
-) {
-    if fov_recompute {
-        // Recompute FOV if needed (the player moved or an object updated)
-        let player = &objects[PLAYER];
-        tcod.fov.compute_fov(
-            player.x,
-            player.y,
-            TORCH_RADIUS,
-            FOV_LIGHT_WALLS,
-            FOV_ALGO
+/// The experience the player needs to reach their next level
+fn level_up_xp(player: &Object) -> i32 {
+    LEVEL_UP_BASE + player.level * LEVEL_UP_FACTOR
+}
+
+/// Show the player's level, experience and stats (both their own and with
+/// their equipment), and how far they have come, until any key is pressed
+fn character_sheet(tcod: &mut Tcod, game: &Game, player: &Object) {
+    let fighter = match player.fighter {
+        Some(fighter) => fighter,
+        None => return,
+    };
+    msgbox(
+        &format!(
+            "Character\n\n\
+            Level: {}\n\
+            Experience: {}\n\
+            Next level at: {}\n\n\
+            Maximum HP: {} ({} with equipment)\n\
+            Power: {} ({} with equipment)\n\
+            Defence: {} ({} with equipment)\n\n\
+            Dungeon level: {}\n\
+            Turn: {}\n\
+            Kills: {}\n",
+            player.level,
+            fighter.xp,
+            level_up_xp(player),
+            fighter.max_hp,
+            player.max_hp(game),
+            fighter.power,
+            player.power(game),
+            fighter.defence,
+            player.defence(game),
+            game.dungeon_level,
+            game.turn,
+            game.kills,
+        ),
+        CHARACTER_SCREEN_WIDTH,
+        &mut tcod.root,
+    );
+}
+
+/// Whether the player has enough experience to level up
+fn can_level_up(player: &Object) -> bool {
+    player
+        .fighter
+        .is_some_and(|fighter| fighter.xp >= level_up_xp(player))
+}
+
+/// Ask the player which stat to raise on levelling up, until they choose one.
+///
+/// Returns `None` only if the window was closed.
+fn level_up_menu(tcod: &mut Tcod, player: &Object) -> Option<Stat> {
+    let fighter = player.fighter?;
+    loop {
+        if tcod.root.window_closed() {
+            return None;
+        }
+        let choice = menu(
+            "Level up! Choose a stat to raise:\n",
+            &[
+                format!(
+                    "Constitution (+{} HP, from {})",
+                    LEVEL_UP_HP,
+                    fighter.max_hp
+                ),
+                format!("Strength (+1 attack, from {})", fighter.power),
+                format!("Agility (+1 defence, from {})", fighter.defence),
+            ],
+            LEVEL_SCREEN_WIDTH,
+            &mut tcod.root,
         );
+        match choice {
+            Some(0) => return Some(Stat::Constitution),
+            Some(1) => return Some(Stat::Strength),
+            Some(2) => return Some(Stat::Agility),
+            _ => {},
+        }
+    }
+}
+
+/// Level the player up, spending the experience it takes and raising the
+/// chosen stat
+fn level_up(stat: Stat, game: &mut Game, objects: &mut [Object]) {
+    let player = &mut objects[PLAYER];
+    let level_up_xp = level_up_xp(player);
+    let fighter = match player.fighter.as_mut() {
+        Some(fighter) => fighter,
+        None => return,
+    };
+
+    fighter.xp -= level_up_xp;
+    match stat {
+        Stat::Constitution => {
+            fighter.max_hp += LEVEL_UP_HP;
+            fighter.hp += LEVEL_UP_HP;
+        },
+        Stat::Strength => fighter.power += 1,
+        Stat::Agility => fighter.defence += 1,
     }
 
+    player.level += 1;
+    game.messages.add(
+        format!(
+            "Your battle skills grow stronger! You reached level {}!",
+            player.level
+        ),
+        YELLOW,
+        MessageCategory::System,
+    );
+}
+
+/// Advance to the next level
+fn next_level(fov: &mut FovMap, game: &mut Game, objects: &mut Vec<Object>) {
+    game.messages.add(
+        "You take a moment to rest, and recover your strength.",
+        VIOLET,
+        MessageCategory::Lore,
+    );
+    let heal_hp = objects[PLAYER].max_hp(game) / 2;
+    objects[PLAYER].heal(heal_hp, game);
+
+    game.messages.add(
+        "After a rare moment of peace, you descend deeper into the heart of \
+        the dungeon…",
+        RED,
+        MessageCategory::Lore,
+    );
+    game.dungeon_level += 1;
+
+    // The player is the first object, so this keeps them and drops everything
+    // that was on the previous level
+    objects.truncate(PLAYER + 1);
+    game.map = make_map(objects, game.dungeon_level, &mut game.rng);
+    initialise_fov(fov, &game.map);
+}
+
+fn render_all(tcod: &mut Tcod, game: &Game, objects: &[Object]) {
     let mut to_draw: Vec<_> = objects
         .iter()
-        .filter(|o| tcod.fov.is_in_fov(o.x, o.y))
+        .filter(|o| {
+            tcod.fov.is_in_fov(o.x, o.y)
+                || (o.always_visible
+                    && game.map[o.x as usize][o.y as usize].explored)
+        })
         .collect();
     // Sort so that non-blocking objects come first
     to_draw.sort_by(|o1, o2| {
@@ -581,9 +3083,7 @@ fn render_all(
     });
     // Draw all objects in the list
     for object in &to_draw {
-        if tcod.fov.is_in_fov(object.x, object.y) {
-            object.draw(&mut tcod.con);
-        }
+        object.draw(&mut tcod.con);
     }
 
     // Go through all tiles, and set their background colour
@@ -601,14 +3101,7 @@ fn render_all(
                 (true, false) => COLOUR_LIGHT_GROUND,
             };
 
-            let explored = &mut game
-                .map[x as usize][y as usize]
-                .explored;
-            if visible {
-                // Since it's visible, explore it
-                *explored = true;
-            }
-            if *explored {
+            if game.map[x as usize][y as usize].explored {
                 // Show explored tiles only (any visible tile is explored
                 // already)
                 tcod.con.set_char_background(
@@ -640,9 +3133,7 @@ fn render_all(
     let hp = objects[PLAYER]
         .fighter
         .map_or(0, |f| f.hp);
-    let max_hp = objects[PLAYER]
-        .fighter
-        .map_or(0, |f| f.max_hp);
+    let max_hp = objects[PLAYER].max_hp(game);
     render_bar(
         &mut tcod.panel,
         1,
@@ -655,22 +3146,47 @@ fn render_all(
         DARKER_RED,
     );
 
+    let xp = objects[PLAYER]
+        .fighter
+        .map_or(0, |f| f.xp);
+    render_bar(
+        &mut tcod.panel,
+        1,
+        2,
+        BAR_WIDTH,
+        "XP",
+        xp,
+        level_up_xp(&objects[PLAYER]),
+        LIGHT_VIOLET,
+        DARKER_VIOLET,
+    );
+
+    tcod.panel.set_default_foreground(WHITE);
+    tcod.panel.print_ex(
+        1,
+        3,
+        BackgroundFlag::None,
+        TextAlignment::Left,
+        format!("Dungeon level: {}", game.dungeon_level),
+    );
+
     // Print the game messages, one line at a time
     let mut y = MSG_HEIGHT as i32;
-    for &(ref msg, colour) in game.messages.iter().rev() {
+    for message in game.messages.iter().rev() {
+        let text = message.full_text();
         let msg_height = tcod.panel.get_height_rect(
             MSG_X,
             y,
             MSG_WIDTH,
             0,
-            msg
+            &text
         );
         y -= msg_height;
         if y < 0 {
             break;
         }
-        tcod.panel.set_default_foreground(colour);
-        tcod.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, msg);
+        tcod.panel.set_default_foreground(message.colour);
+        tcod.panel.print_rect(MSG_X, y, MSG_WIDTH, 0, &text);
     }
 
     // Blit the contents of `panel` to the root console
@@ -702,84 +3218,535 @@ fn create_room(room: Rect, map: &mut Map) {
             map[x as usize][y as usize] = Tile::empty();
         }
     }
-}
+}
+
+fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
+    // Horizontal tunnel. `min()` and `max()` are used in the case of `x1 > x2`
+    for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
+        map[x as usize][y as usize] = Tile::empty();
+    }
+}
+
+fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
+    // Vertical tunnel
+    for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
+        map[x as usize][y as usize] = Tile::empty();
+    }
+}
+
+/// The tiles around the given one that are on the map, including the
+/// diagonals
+fn neighbours(x: i32, y: i32) -> impl Iterator<Item = (i32, i32)> {
+    const DIRECTIONS: [(i32, i32); 8] = [
+        (-1, -1), (0, -1), (1, -1),
+        (-1, 0), (1, 0),
+        (-1, 1), (0, 1), (1, 1),
+    ];
+    DIRECTIONS
+        .into_iter()
+        .map(move |(dx, dy)| (x + dx, y + dy))
+        .filter(|&(nx, ny)| {
+            (0..MAP_WIDTH).contains(&nx) && (0..MAP_HEIGHT).contains(&ny)
+        })
+}
+
+/// The floor tiles that can be reached from the start, in order of their
+/// distance from it
+fn flood_fill(map: &Map, start: (i32, i32)) -> Vec<(i32, i32)> {
+    let mut visited =
+        vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut tiles = vec![start];
+    visited[start.0 as usize][start.1 as usize] = true;
+
+    // The tiles double as the queue of a breadth-first search
+    let mut next = 0;
+    while next < tiles.len() {
+        let (x, y) = tiles[next];
+        next += 1;
+        for (nx, ny) in [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)] {
+            let on_map = (0..MAP_WIDTH).contains(&nx)
+                && (0..MAP_HEIGHT).contains(&ny);
+            if on_map
+                && !visited[nx as usize][ny as usize]
+                && !map[nx as usize][ny as usize].blocked {
+                visited[nx as usize][ny as usize] = true;
+                tiles.push((nx, ny));
+            }
+        }
+    }
+    tiles
+}
+
+/// Turn every floor tile that isn't connected to the biggest open region into
+/// wall, and return the tiles of that region
+fn keep_largest_region(map: &mut Map) -> Vec<(i32, i32)> {
+    let mut regions: Vec<Vec<(i32, i32)>> = Vec::new();
+    let mut in_region =
+        vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    for x in 0..MAP_WIDTH {
+        for y in 0..MAP_HEIGHT {
+            if !map[x as usize][y as usize].blocked
+                && !in_region[x as usize][y as usize] {
+                let region = flood_fill(map, (x, y));
+                for &(rx, ry) in &region {
+                    in_region[rx as usize][ry as usize] = true;
+                }
+                regions.push(region);
+            }
+        }
+    }
+
+    // Keep the biggest region; if several are as big, the first one found in
+    // scan order wins
+    let largest = regions
+        .iter()
+        .enumerate()
+        .max_by_key(|&(index, region)| (region.len(), Reverse(index)))
+        .map_or(0, |(index, _)| index);
+    for (index, region) in regions.iter().enumerate() {
+        if index != largest {
+            for &(x, y) in region {
+                map[x as usize][y as usize] = Tile::wall();
+            }
+        }
+    }
+
+    if regions.is_empty() {
+        vec![]
+    } else {
+        regions.swap_remove(largest)
+    }
+}
+
+/// Split floor tiles into square areas of the given size for placing objects,
+/// leaving out areas with too little floor to bother with
+fn split_into_areas(tiles: &[(i32, i32)], size: i32) -> Vec<Vec<(i32, i32)>> {
+    let columns = (MAP_WIDTH + size - 1) / size;
+    let rows = (MAP_HEIGHT + size - 1) / size;
+    let mut areas = vec![Vec::new(); (columns * rows) as usize];
+    for &(x, y) in tiles {
+        areas[(x / size + y / size * columns) as usize].push((x, y));
+    }
+    areas.retain(|area| area.len() as i32 >= size * size / 4);
+    areas
+}
+
+/// Stamp a few of the vaults that belong at this depth into the map, along
+/// with their monsters and items
+fn place_vaults(
+    map: &mut Map,
+    objects: &mut Vec<Object>,
+    level: u32,
+    rng: &mut GameRng,
+) {
+    let vaults: Vec<&Vault> = VAULTS
+        .iter()
+        .filter(|vault| (vault.min_level..=vault.max_level).contains(&level))
+        .collect();
+
+    // Where the vaults are, so that none of them are dug into
+    let mut placed = vec![];
+    for _ in 0..MAX_VAULTS {
+        if rng.gen_range(0, 100) >= VAULT_CHANCE {
+            continue;
+        }
+        let vault = match rng.choose(&vaults) {
+            Some(vault) => vault,
+            None => return,
+        };
+        let layout = vault.layout(rng.gen_range(0, 4), rng.gen());
+
+        // Try a few spots, and give up on this vault if none of them fit
+        for _ in 0..VAULT_PLACEMENT_ATTEMPTS {
+            if stamp_vault(&layout, &mut placed, map, objects, level, rng) {
+                break;
+            }
+        }
+    }
+}
+
+/// Stamp the vault's layout into the map at a random spot, if it fits there,
+/// away from the vaults already placed, and can be joined to the level.
+///
+/// Returns whether it was stamped, adding it to the placed vaults if so.
+fn stamp_vault(
+    layout: &[Vec<char>],
+    placed: &mut Vec<Rect>,
+    map: &mut Map,
+    objects: &mut Vec<Object>,
+    level: u32,
+    rng: &mut GameRng,
+) -> bool {
+    let height = layout.len() as i32;
+    let width = layout[0].len() as i32;
+    if width + 2 > MAP_WIDTH || height + 2 > MAP_HEIGHT {
+        return false;
+    }
+
+    // Keep a wall between the vault and the map's border
+    let x = rng.gen_range(1, MAP_WIDTH - width);
+    let y = rng.gen_range(1, MAP_HEIGHT - height);
+
+    // Keep a tile of rock between vaults, so their walls aren't shared
+    let vault = Rect::new(x, y, width, height);
+    if placed.iter().any(|other| vault.intersects_with(other)) {
+        return false;
+    }
+
+    // The vault and the tiles around it must be solid rock, so that it
+    // doesn't break into any room or tunnel
+    for tile_x in (x - 1)..=(x + width) {
+        for tile_y in (y - 1)..=(y + height) {
+            if !map[tile_x as usize][tile_y as usize].blocked {
+                return false;
+            }
+        }
+    }
+
+    // Dig from the entrance to the nearest floor, around the vault
+    let entrance = layout.iter().enumerate().find_map(|(row, tiles)| {
+        let column = tiles.iter().position(|&tile| tile == '+')?;
+        Some((x + column as i32, y + row as i32))
+    });
+    let vaults = [placed.as_slice(), &[vault]].concat();
+    let tunnel = match entrance.and_then(|entrance| {
+        tunnel_to_floor(entrance, &vaults, map)
+    }) {
+        Some(tunnel) => tunnel,
+        None => return false,
+    };
+    for (tile_x, tile_y) in tunnel {
+        map[tile_x as usize][tile_y as usize] = Tile::empty();
+    }
+
+    for (row, tiles) in layout.iter().enumerate() {
+        for (column, &tile) in tiles.iter().enumerate() {
+            let (tile_x, tile_y) = (x + column as i32, y + row as i32);
+            match tile {
+                ' ' => continue,
+                '#' => map[tile_x as usize][tile_y as usize] = Tile::wall(),
+                _ => map[tile_x as usize][tile_y as usize] = Tile::empty(),
+            }
+
+            let monster = match tile {
+                'O' => Some(MonsterKind::Orc),
+                'T' => Some(MonsterKind::Troll),
+                'M' => Some(
+                    WeightedChoice::new(&mut monster_chances(level))
+                        .ind_sample(rng),
+                ),
+                _ => None,
+            };
+            let item = match tile {
+                '!' => Some(Item::Heal),
+                '/' => Some(Item::Sword),
+                '[' => Some(Item::Shield),
+                ']' => Some(Item::Armour),
+                '?' => Some(
+                    WeightedChoice::new(&mut item_chances(level))
+                        .ind_sample(rng),
+                ),
+                _ => None,
+            };
+            if let Some(kind) = monster {
+                objects.push(make_monster(kind, tile_x, tile_y));
+            }
+            if let Some(kind) = item {
+                objects.push(make_item(kind, tile_x, tile_y));
+            }
+        }
+    }
 
-fn create_h_tunnel(x1: i32, x2: i32, y: i32, map: &mut Map) {
-    // Horizontal tunnel. `min()` and `max()` are used in the case of `x1 > x2`
-    for x in cmp::min(x1, x2)..(cmp::max(x1, x2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
-    }
+    placed.push(vault);
+    true
 }
 
-fn create_v_tunnel(y1: i32, y2: i32, x: i32, map: &mut Map) {
-    // Vertical tunnel
-    for y in cmp::min(y1, y2)..(cmp::max(y1, y2) + 1) {
-        map[x as usize][y as usize] = Tile::empty();
+/// The shortest tunnel from the entrance (on the edge of one of the vaults) to
+/// the nearest floor tile outside the vaults, going around all of them and
+/// staying off the map's border. The tunnel includes the entrance.
+fn tunnel_to_floor(
+    entrance: (i32, i32),
+    vaults: &[Rect],
+    map: &Map,
+) -> Option<Vec<(i32, i32)>> {
+    let in_vault = |x: i32, y: i32| {
+        vaults.iter().any(|vault| {
+            (vault.x1..vault.x2).contains(&x)
+                && (vault.y1..vault.y2).contains(&y)
+        })
+    };
+
+    // Breadth-first search, remembering where each tile was reached from
+    let mut came_from =
+        vec![vec![None; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut queue = vec![entrance];
+    let mut next = 0;
+    while next < queue.len() {
+        let (x, y) = queue[next];
+        next += 1;
+        for (nx, ny) in [(x - 1, y), (x + 1, y), (x, y - 1), (x, y + 1)] {
+            let inside_border = (1..MAP_WIDTH - 1).contains(&nx)
+                && (1..MAP_HEIGHT - 1).contains(&ny);
+            if !inside_border
+                || in_vault(nx, ny)
+                || came_from[nx as usize][ny as usize].is_some() {
+                continue;
+            }
+            came_from[nx as usize][ny as usize] = Some((x, y));
+
+            if !map[nx as usize][ny as usize].blocked {
+                // Found the floor, so follow the way back to the entrance
+                let mut tunnel = vec![];
+                let mut tile = (x, y);
+                while tile != entrance {
+                    tunnel.push(tile);
+                    tile = came_from[tile.0 as usize][tile.1 as usize]?;
+                }
+                tunnel.push(entrance);
+                return Some(tunnel);
+            }
+            queue.push((nx, ny));
+        }
     }
+    None
 }
 
-fn place_objects(room: Rect, objects: &mut Vec<Object>, map: &Map) {
+/// Put a random number of monsters and items on random tiles of the area
+fn place_objects(
+    area: &[(i32, i32)],
+    objects: &mut Vec<Object>,
+    map: &Map,
+    level: u32,
+    rng: &mut GameRng,
+) {
+    // Maximum number of monsters per area
+    let max_monsters = from_dungeon_level(&[(2, 1), (3, 4), (5, 6)], level);
+
+    let monster_chances = &mut monster_chances(level);
+    let monster_choice = WeightedChoice::new(monster_chances);
+
+    // Maximum number of items per area
+    let max_items = from_dungeon_level(&[(1, 1), (2, 4)], level);
+
+    let item_chances = &mut item_chances(level);
+    let item_choice = WeightedChoice::new(item_chances);
+
     // Choose random number of monsters
-    let num_monsters = rand::thread_rng()
-        .gen_range(0, MAX_ROOM_MONSTERS + 1);
+    let num_monsters = rng.gen_range(0, max_monsters + 1);
 
     for _ in 0..num_monsters {
         // Choose random spot for the monster
-        let x = rand::thread_rng()
-            .gen_range(room.x1 + 1, room.x2);
-        let y = rand::thread_rng()
-            .gen_range(room.y1 + 1, room.y2);
+        let (x, y) = match rng.choose(area) {
+            Some(&tile) => tile,
+            None => return,
+        };
 
         if !is_blocked(x, y, map, objects) {
-            // 80% chance of getting an Orc
-            let mut monster = if rand::random::<f32>() < 0.8 {
-                // Create an Orc
-                let mut orc = Object::new(
-                    x,
-                    y,
-                    'O',
-                    colors::DESATURATED_GREEN,
-                    "Orc",
-                    true
-                );
-                orc.fighter = Some(Fighter {
-                    max_hp: 10,
-                    hp: 10,
-                    defence: 0,
-                    power: 3,
-                    on_death: DeathCallback::Monster,
-                });
-                orc.ai = Some(Ai::Basic);
+            let kind = monster_choice.ind_sample(rng);
+            objects.push(make_monster(kind, x, y));
+        }
+    }
 
-                orc
-            } else {
-                // Create a Troll
-                let mut troll = Object::new(
-                    x,
-                    y,
-                    'T',
-                    colors::DARKER_GREEN,
-                    "Troll",
-                    true
-                );
-                troll.fighter = Some(Fighter {
-                    max_hp: 16,
-                    hp: 16,
-                    defence: 1,
-                    power: 4,
-                    on_death: DeathCallback::Monster,
-                });
-                troll.ai = Some(Ai::Basic);
+    // Choose random number of items
+    let num_items = rng.gen_range(0, max_items + 1);
 
-                troll
-            };
+    for _ in 0..num_items {
+        // Choose random spot for the item
+        let (x, y) = match rng.choose(area) {
+            Some(&tile) => tile,
+            None => return,
+        };
 
-            monster.alive = true;
-            objects.push(monster);
+        // Only place it if the tile is not blocked
+        if !is_blocked(x, y, map, objects) {
+            let kind = item_choice.ind_sample(rng);
+            objects.push(make_item(kind, x, y));
         }
     }
 }
 
+/// Monster random table, trolls get more common deeper down
+fn monster_chances(level: u32) -> Vec<Weighted<MonsterKind>> {
+    let troll_chance = from_dungeon_level(&[(15, 3), (30, 5), (60, 7)], level);
+    vec![
+        Weighted { weight: 80, item: MonsterKind::Orc },
+        Weighted { weight: troll_chance, item: MonsterKind::Troll },
+    ]
+}
+
+/// Item random table, the stronger items only appear deeper down
+fn item_chances(level: u32) -> Vec<Weighted<Item>> {
+    vec![
+        Weighted { weight: 35, item: Item::Heal },
+        Weighted {
+            weight: from_dungeon_level(&[(25, 4)], level),
+            item: Item::Lightning,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[(25, 6)], level),
+            item: Item::Fireball,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[(10, 2)], level),
+            item: Item::Confuse,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[(5, 4)], level),
+            item: Item::Sword,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[(15, 8)], level),
+            item: Item::Shield,
+        },
+        Weighted {
+            weight: from_dungeon_level(&[(10, 3)], level),
+            item: Item::Armour,
+        },
+    ]
+}
+
+/// Return a value that depends on the dungeon level. The table lists
+/// `(value, level)` pairs, sorted by level: each value applies from its level
+/// onwards, and the value before the first level is 0.
+fn from_dungeon_level(table: &[(u32, u32)], level: u32) -> u32 {
+    table
+        .iter()
+        .rev()
+        .find(|&&(_, from_level)| level >= from_level)
+        .map_or(0, |&(value, _)| value)
+}
+
+/// Create a monster of the given kind
+fn make_monster(kind: MonsterKind, x: i32, y: i32) -> Object {
+    let mut monster = match kind {
+        MonsterKind::Orc => {
+            let mut orc = Object::new(
+                x,
+                y,
+                'O',
+                colors::DESATURATED_GREEN,
+                "Orc",
+                true
+            );
+            orc.fighter = Some(Fighter {
+                max_hp: 10,
+                hp: 10,
+                defence: 0,
+                power: 3,
+                xp: 35,
+                on_death: DeathCallback::Monster,
+            });
+            orc
+        },
+        MonsterKind::Troll => {
+            let mut troll = Object::new(
+                x,
+                y,
+                'T',
+                colors::DARKER_GREEN,
+                "Troll",
+                true
+            );
+            troll.fighter = Some(Fighter {
+                max_hp: 16,
+                hp: 16,
+                defence: 1,
+                power: 4,
+                xp: 100,
+                on_death: DeathCallback::Monster,
+            });
+            troll
+        },
+    };
+
+    monster.ai = Some(Ai::Basic);
+    monster.alive = true;
+    monster
+}
+
+/// Create an item of the given kind
+fn make_item(kind: Item, x: i32, y: i32) -> Object {
+    let mut item = match kind {
+        Item::Heal => {
+            Object::new(x, y, '!', VIOLET, "healing potion", false)
+        },
+        Item::Lightning => Object::new(
+            x,
+            y,
+            '#',
+            LIGHT_YELLOW,
+            "scroll of lightning bolt",
+            false
+        ),
+        Item::Fireball => Object::new(
+            x,
+            y,
+            '#',
+            LIGHT_YELLOW,
+            "scroll of fireball",
+            false
+        ),
+        Item::Confuse => Object::new(
+            x,
+            y,
+            '#',
+            LIGHT_YELLOW,
+            "scroll of confusion",
+            false
+        ),
+        Item::Sword => {
+            let mut sword = Object::new(x, y, '/', SKY, "sword", false);
+            sword.equipment = Some(Equipment {
+                slot: Slot::RightHand,
+                equipped: false,
+                power_bonus: 3,
+                defence_bonus: 0,
+                max_hp_bonus: 0,
+            });
+            sword
+        },
+        Item::Shield => {
+            let mut shield = Object::new(
+                x,
+                y,
+                '[',
+                DARKER_ORANGE,
+                "shield",
+                false
+            );
+            shield.equipment = Some(Equipment {
+                slot: Slot::LeftHand,
+                equipped: false,
+                power_bonus: 0,
+                defence_bonus: 1,
+                max_hp_bonus: 0,
+            });
+            shield
+        },
+        Item::Armour => {
+            let mut armour = Object::new(
+                x,
+                y,
+                ']',
+                DARK_SEPIA,
+                "leather armour",
+                false
+            );
+            armour.equipment = Some(Equipment {
+                slot: Slot::Body,
+                equipped: false,
+                power_bonus: 0,
+                defence_bonus: 1,
+                max_hp_bonus: 5,
+            });
+            armour
+        },
+    };
+
+    item.item = Some(kind);
+    item
+}
+
 fn is_blocked(x: i32, y: i32, map: &Map, objects: &[Object]) -> bool {
     // First, test the map tile
     if map[x as usize][y as usize].blocked {
@@ -832,91 +3799,625 @@ fn player_move_or_attack(
     }
 }
 
-fn move_towards(
-    id: usize, 
-    target_x: i32,
-    target_y: i32,
-    map: &Map,
+/// Step straight towards the target, diagonally unless it's in line. If
+/// that's blocked, try sliding along whichever way is still open.
+fn move_towards(
+    id: usize,
+    target_x: i32,
+    target_y: i32,
+    map: &Map,
+    objects: &mut [Object]
+) {
+    let (x, y) = objects[id].pos();
+    let dx = (target_x - x).signum();
+    let dy = (target_y - y).signum();
+
+    let step = [(dx, dy), (dx, 0), (0, dy)]
+        .into_iter()
+        .find(|&(step_x, step_y)| {
+            (step_x, step_y) != (0, 0)
+                && !is_blocked(x + step_x, y + step_y, map, objects)
+        });
+    if let Some((step_x, step_y)) = step {
+        move_by(id, step_x, step_y, map, objects);
+    }
+}
+
+/// Move one step along the shortest path to the target, walking around other
+/// monsters where that isn't much longer. Falls back on `move_towards` if
+/// there is no path, or it's too long to bother finding.
+fn move_astar(
+    id: usize,
+    target_x: i32,
+    target_y: i32,
+    map: &Map,
+    objects: &mut [Object]
+) {
+    let start = objects[id].pos();
+    match astar_first_step(start, (target_x, target_y), map, objects) {
+        Some((x, y)) => move_by(id, x - start.0, y - start.1, map, objects),
+        None => move_towards(id, target_x, target_y, map, objects),
+    }
+}
+
+/// The first step of the cheapest path from the start to the goal, found
+/// with A*, if there is one of up to `MAX_PATH_LENGTH` steps.
+///
+/// Tiles with blocking objects on them can be walked through at a cost,
+/// since the objects may well have moved by the time they are reached.
+fn astar_first_step(
+    start: (i32, i32),
+    goal: (i32, i32),
+    map: &Map,
+    objects: &[Object],
+) -> Option<(i32, i32)> {
+    let mut occupied =
+        vec![vec![false; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    for object in objects.iter().filter(|object| object.blocks) {
+        occupied[object.x as usize][object.y as usize] = true;
+    }
+
+    // The cheapest known cost of reaching each tile, and where from
+    let mut costs =
+        vec![vec![i32::MAX; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    let mut came_from =
+        vec![vec![None; MAP_HEIGHT as usize]; MAP_WIDTH as usize];
+    costs[start.0 as usize][start.1 as usize] = 0;
+
+    // Tiles to explore, cheapest (counting the distance left) first. The
+    // distance, in steps that can be diagonal, never overestimates the cost,
+    // so the first path to reach the goal is the cheapest.
+    let distance_left = |(x, y): (i32, i32)| {
+        cmp::max((x - goal.0).abs(), (y - goal.1).abs())
+    };
+    let mut open = BinaryHeap::new();
+    open.push(Reverse((distance_left(start), 0, 0, start)));
+
+    while let Some(Reverse((_, cost, steps, (x, y)))) = open.pop() {
+        if (x, y) == goal {
+            // Follow the path back to the step right after the start
+            let mut tile = goal;
+            loop {
+                let previous = came_from[tile.0 as usize][tile.1 as usize]?;
+                if previous == start {
+                    return Some(tile);
+                }
+                tile = previous;
+            }
+        }
+        if cost > costs[x as usize][y as usize] || steps >= MAX_PATH_LENGTH {
+            // Already reached more cheaply, or too far to keep looking
+            continue;
+        }
+
+        for (nx, ny) in neighbours(x, y) {
+            if map[nx as usize][ny as usize].blocked {
+                continue;
+            }
+            let step_cost = if occupied[nx as usize][ny as usize]
+                && (nx, ny) != goal {
+                OCCUPIED_TILE_COST
+            } else {
+                1
+            };
+            let new_cost = cost + step_cost;
+            if new_cost < costs[nx as usize][ny as usize] {
+                costs[nx as usize][ny as usize] = new_cost;
+                came_from[nx as usize][ny as usize] = Some((x, y));
+                open.push(Reverse((
+                    new_cost + distance_left((nx, ny)),
+                    new_cost,
+                    steps + 1,
+                    (nx, ny),
+                )));
+            }
+        }
+    }
+    None
+}
+
+fn ai_take_turn(
+    monster_id: usize,
+    fov: &FovMap,
+    monster_maps: &MonsterMaps,
+    game: &mut Game,
+    objects: &mut [Object]
+) {
+    use Ai::*;
+
+    // Take the AI out while it acts, and put back whatever it turned into
+    if let Some(ai) = objects[monster_id].ai.take() {
+        let new_ai = match ai {
+            Basic => ai_basic(monster_id, fov, monster_maps, game, objects),
+            Confused {
+                previous_ai,
+                num_turns,
+            } => ai_confused(
+                monster_id,
+                game,
+                objects,
+                previous_ai,
+                num_turns,
+            ),
+        };
+        objects[monster_id].ai = Some(new_ai);
+    }
+}
+
+fn ai_basic(
+    monster_id: usize,
+    fov: &FovMap,
+    monster_maps: &MonsterMaps,
+    game: &mut Game,
+    objects: &mut [Object]
+) -> Ai {
+    // A basic monster takes its turn. If you can see it, it can see you.
+    let (monster_x, monster_y) = objects[monster_id].pos();
+    if !fov.is_in_fov(monster_x, monster_y) {
+        return Ai::Basic;
+    }
+
+    // Badly hurt monsters run away, and only fight back when cornered
+    let fleeing = objects[monster_id].fighter.is_some_and(|fighter| {
+        fighter.hp * 100 <= fighter.max_hp * FLEE_HP_PERCENT
+    });
+    if fleeing {
+        let escape = monster_maps.away_from_player.downhill(
+            monster_x,
+            monster_y,
+            &game.map,
+            objects,
+        );
+        if let Some((x, y)) = escape {
+            objects[monster_id].set_pos(x, y);
+            return Ai::Basic;
+        }
+    }
+
+    if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
+        // Move towards the player if far away, going around any monsters in
+        // the way
+        let towards_player = monster_maps.to_player.downhill(
+            monster_x,
+            monster_y,
+            &game.map,
+            objects,
+        );
+        match towards_player {
+            Some((x, y)) => objects[monster_id].set_pos(x, y),
+            None => {
+                let (player_x, player_y) = objects[PLAYER].pos();
+                move_astar(
+                    monster_id,
+                    player_x,
+                    player_y,
+                    &game.map,
+                    objects
+                );
+            },
+        }
+    } else if objects[PLAYER]
+        .fighter
+        .map_or(false, |f| f.hp > 0) {
+        // Close enough, attack! (if the player is still alive)
+        let (monster, player) = mut_two(
+            monster_id,
+            PLAYER,
+            objects
+        );
+        monster.attack(player, game);
+    }
+    Ai::Basic
+}
+
+fn ai_confused(
+    monster_id: usize,
+    game: &mut Game,
+    objects: &mut [Object],
+    previous_ai: Box<Ai>,
+    num_turns: i32,
+) -> Ai {
+    if num_turns >= 0 {
+        // Still confused: move in a random direction, and decrease the number
+        // of turns confused
+        let dx = game.rng.gen_range(-1, 2);
+        let dy = game.rng.gen_range(-1, 2);
+        move_by(monster_id, dx, dy, &game.map, objects);
+        Ai::Confused {
+            previous_ai,
+            num_turns: num_turns - 1,
+        }
+    } else {
+        // Restore the previous AI (this one will be deleted)
+        game.messages.add(
+            format!("The {} is no longer confused!", objects[monster_id].name),
+            RED,
+            MessageCategory::Combat,
+        );
+        *previous_ai
+    }
+}
+
+/// Mutably borrows two *separate* elements from a given slice.
+/// Panics when the indices are equal or out of bounds
+fn mut_two<T>(
+    first_index: usize,
+    second_index: usize,
+    items: &mut [T]
+) -> (&mut T, &mut T) {
+    assert!(first_index != second_index);
+    let split_at_index = cmp::max(first_index, second_index);
+    let (first_slice, second_slice) = items
+        .split_at_mut(split_at_index);
+    if first_index < second_index {
+        (&mut first_slice[first_index], &mut second_slice[0])
+    } else {
+        (&mut second_slice[0], &mut first_slice[second_index])
+    }
+}
+
+/// Add the item to the player's inventory and remove it from the map
+fn pick_item_up(object_id: usize, game: &mut Game, objects: &mut Vec<Object>) {
+    if game.inventory.len() >= MAX_INVENTORY_SIZE {
+        game.messages.add(
+            format!(
+                "Your inventory is full, cannot pick up {}.",
+                objects[object_id].name
+            ),
+            RED,
+            MessageCategory::Item,
+        );
+    } else {
+        // `swap_remove()` never moves the player, since the player is always
+        // the first object and never an item
+        let mut item = objects.swap_remove(object_id);
+        game.messages.add(
+            format!("You picked up a {}!", item.name),
+            GREEN,
+            MessageCategory::Item,
+        );
+
+        // Automatically equip it, if the corresponding slot is unused
+        if let Some(slot) = item.equipment.map(|e| e.slot) {
+            if get_equipped_in_slot(slot, &game.inventory).is_none() {
+                item.equip(&mut game.messages);
+            }
+        }
+
+        game.inventory.push(item);
+    }
+}
+
+/// Remove the item from the player's inventory and put it back on the map, at
+/// the player's feet
+fn drop_item(
+    inventory_id: usize,
+    game: &mut Game,
+    objects: &mut Vec<Object>
+) {
+    let mut item = game.inventory.remove(inventory_id);
+    if item.equipment.is_some() {
+        item.dequip(&mut game.messages);
+        clamp_player_hp(game, objects);
+    }
+    item.set_pos(objects[PLAYER].x, objects[PLAYER].y);
+    game.messages.add(
+        format!("You dropped a {}.", item.name),
+        YELLOW,
+        MessageCategory::Item,
+    );
+    objects.push(item);
+}
+
+/// Use the item in the given inventory slot (on the target, if it needs one),
+/// destroying it if it was used up
+fn use_item(
+    inventory_id: usize,
+    target: Option<Target>,
+    fov: &FovMap,
+    game: &mut Game,
+    objects: &mut [Object]
+) -> UseResult {
+    use Item::*;
+
+    // Just call the "use function" if it is defined
+    if let Some(item) = game.inventory[inventory_id].item {
+        let on_use = match item {
+            Heal => cast_heal,
+            Lightning => cast_lightning,
+            Confuse => cast_confuse,
+            Fireball => cast_fireball,
+            Sword | Shield | Armour => toggle_equipment,
+        };
+
+        // Scripted actions can name any target, so make sure it is one the
+        // player could have picked in targeting mode
+        if let Some(target_kind) = item.target_kind() {
+            let reachable = target.is_some_and(|target| {
+                is_reachable_target(target, target_kind, fov, objects)
+            });
+            if !reachable {
+                game.messages.add(
+                    format!(
+                        "That is not a valid target for the {}.",
+                        game.inventory[inventory_id].name
+                    ),
+                    WHITE,
+                    MessageCategory::System,
+                );
+                return UseResult::Cancelled;
+            }
+        }
+
+        let result = on_use(inventory_id, target, fov, game, objects);
+        match result {
+            UseResult::UsedUp => {
+                // Destroy after use, unless it was cancelled for some reason
+                game.inventory.remove(inventory_id);
+            },
+            UseResult::UsedAndKept => {},
+            UseResult::Cancelled => {
+                game.messages.add("Cancelled", WHITE, MessageCategory::System);
+            },
+        }
+        result
+    } else {
+        game.messages.add(
+            format!(
+                "The {} cannot be used.",
+                game.inventory[inventory_id].name
+            ),
+            WHITE,
+            MessageCategory::Item,
+        );
+        UseResult::Cancelled
+    }
+}
+
+fn cast_heal(
+    _inventory_id: usize,
+    _target: Option<Target>,
+    _fov: &FovMap,
+    game: &mut Game,
+    objects: &mut [Object]
+) -> UseResult {
+    // Heal the player
+    let max_hp = objects[PLAYER].max_hp(game);
+    if let Some(fighter) = objects[PLAYER].fighter {
+        if fighter.hp >= max_hp {
+            game.messages.add(
+                "You are already at full health.",
+                RED,
+                MessageCategory::Item,
+            );
+            return UseResult::Cancelled;
+        }
+        game.messages.add(
+            "Your wounds start to feel better!",
+            LIGHT_VIOLET,
+            MessageCategory::Item,
+        );
+        objects[PLAYER].heal(HEAL_AMOUNT, game);
+        return UseResult::UsedUp;
+    }
+    UseResult::Cancelled
+}
+
+/// Bring the player's HP down to their maximum, which drops when equipment
+/// with a max HP bonus is dequipped. Call after every dequip.
+fn clamp_player_hp(game: &Game, objects: &mut [Object]) {
+    let max_hp = objects[PLAYER].max_hp(game);
+    if let Some(fighter) = objects[PLAYER].fighter.as_mut() {
+        fighter.hp = cmp::min(fighter.hp, max_hp);
+    }
+}
+
+fn toggle_equipment(
+    inventory_id: usize,
+    _target: Option<Target>,
+    _fov: &FovMap,
+    game: &mut Game,
+    objects: &mut [Object]
+) -> UseResult {
+    let equipment = match game.inventory[inventory_id].equipment {
+        Some(equipment) => equipment,
+        None => return UseResult::Cancelled,
+    };
+    if equipment.equipped {
+        game.inventory[inventory_id].dequip(&mut game.messages);
+    } else {
+        // If the slot is already being used, dequip whatever is there first
+        if let Some(current) = get_equipped_in_slot(
+            equipment.slot,
+            &game.inventory
+        ) {
+            game.inventory[current].dequip(&mut game.messages);
+        }
+        game.inventory[inventory_id].equip(&mut game.messages);
+    }
+    clamp_player_hp(game, objects);
+
+    UseResult::UsedAndKept
+}
+
+/// Return the inventory index of the item equipped in the given slot, if any
+fn get_equipped_in_slot(slot: Slot, inventory: &[Object]) -> Option<usize> {
+    inventory.iter().position(|item| {
+        item.equipment
+            .is_some_and(|e| e.equipped && e.slot == slot)
+    })
+}
+
+fn cast_lightning(
+    _inventory_id: usize,
+    _target: Option<Target>,
+    fov: &FovMap,
+    game: &mut Game,
+    objects: &mut [Object]
+) -> UseResult {
+    // Find the closest enemy (inside a maximum range) and damage it
+    let monster_id = closest_monster(fov, objects, LIGHTNING_RANGE);
+    if let Some(monster_id) = monster_id {
+        game.messages.add(
+            format!(
+                "A lightning bolt strikes the {} with a loud thunder! The \
+                damage is {} hit points.",
+                objects[monster_id].name,
+                LIGHTNING_DAMAGE
+            ),
+            LIGHT_BLUE,
+            MessageCategory::Combat,
+        );
+        if let Some(xp) = objects[monster_id].take_damage(
+            LIGHTNING_DAMAGE,
+            game
+        ) {
+            if let Some(fighter) = objects[PLAYER].fighter.as_mut() {
+                fighter.xp += xp;
+            }
+        }
+        UseResult::UsedUp
+    } else {
+        // No enemy found within the maximum range
+        game.messages.add(
+            "No enemy is close enough to strike.",
+            RED,
+            MessageCategory::Item,
+        );
+        UseResult::Cancelled
+    }
+}
+
+fn cast_confuse(
+    _inventory_id: usize,
+    target: Option<Target>,
+    _fov: &FovMap,
+    game: &mut Game,
     objects: &mut [Object]
-) {
-    // Vector from this object to the target, and distance
-    let dx = target_x - objects[id].x;
-    let dy = target_y - objects[id].y;
-    let distance = ((dx.pow(2) + dy.pow(2)) as f32).sqrt();
-
-    // Normalize it to length 1 (preserving direction), then round it and
-    // convert to integer so the movement is restricted to map grid
-    let dx = (dx as f32 / distance).round() as i32;
-    let dy = (dy as f32 / distance).round() as i32;
-    move_by(id, dx, dy, map, objects);
+) -> UseResult {
+    // Confuse the monster the player picked
+    if let Some(Target::Monster(monster_id)) = target {
+        let old_ai = objects[monster_id].ai.take().unwrap_or(Ai::Basic);
+        // Replace the monster's AI with a "confused" one; after some turns it
+        // will restore the old AI
+        objects[monster_id].ai = Some(Ai::Confused {
+            previous_ai: Box::new(old_ai),
+            num_turns: CONFUSE_NUM_TURNS,
+        });
+        game.messages.add(
+            format!(
+                "The eyes of the {} look vacant, as it starts to stumble \
+                around!",
+                objects[monster_id].name
+            ),
+            LIGHT_GREEN,
+            MessageCategory::Combat,
+        );
+        UseResult::UsedUp
+    } else {
+        UseResult::Cancelled
+    }
 }
 
-fn ai_take_turn(
-    monster_id: usize,
-    tcod: &Tcod,
+fn cast_fireball(
+    _inventory_id: usize,
+    target: Option<Target>,
+    _fov: &FovMap,
     game: &mut Game,
     objects: &mut [Object]
-) {
-    // A basic monster takes its turn. If you can see it, it can see you.
-    let (monster_x, monster_y) = objects[monster_id].pos();
-    if tcod.fov.is_in_fov(monster_x, monster_y) {
-        if objects[monster_id].distance_to(&objects[PLAYER]) >= 2.0 {
-            // Move towards the player if far away
-            let (player_x, player_y) = objects[PLAYER].pos();
-            move_towards(
-                monster_id,
-                player_x,
-                player_y,
-                &game.map,
-                objects
-            );
-        } else if objects[PLAYER]
-            .fighter
-            .map_or(false, |f| f.hp > 0) {
-            // Close enough, attack! (if the player is still alive)
-            let (monster, player) = mut_two(
-                monster_id,
-                PLAYER,
-                objects
+) -> UseResult {
+    // Throw the fireball at the tile the player picked
+    let (x, y) = match target {
+        Some(Target::Tile(x, y)) => (x, y),
+        _ => return UseResult::Cancelled,
+    };
+    game.messages.add(
+        format!(
+            "The fireball explodes, burning everything within {} tiles!",
+            FIREBALL_RADIUS
+        ),
+        ORANGE,
+        MessageCategory::Combat,
+    );
+
+    // Every fighter in the blast is burned, the player included
+    let mut xp_to_gain = 0;
+    for object in objects.iter_mut() {
+        if object.distance(x, y) <= FIREBALL_RADIUS as f32
+            && object.fighter.is_some() {
+            game.messages.add(
+                format!(
+                    "The {} gets burned for {} hit points.",
+                    object.name,
+                    FIREBALL_DAMAGE
+                ),
+                ORANGE,
+                MessageCategory::Combat,
             );
-            monster.attack(player, game);
+            if let Some(xp) = object.take_damage(FIREBALL_DAMAGE, game) {
+                xp_to_gain += xp;
+            }
         }
     }
+    if let Some(fighter) = objects[PLAYER].fighter.as_mut() {
+        fighter.xp += xp_to_gain;
+    }
+
+    UseResult::UsedUp
 }
 
-/// Mutably borrows two *separate* elements from a given slice.
-/// Panics when the indices are equal or out of bounds
-fn mut_two<T>(
-    first_index: usize,
-    second_index: usize,
-    items: &mut [T]
-) -> (&mut T, &mut T) {
-    assert!(first_index != second_index);
-    let split_at_index = cmp::max(first_index, second_index);
-    let (first_slice, second_slice) = items
-        .split_at_mut(split_at_index);
-    if first_index < second_index {
-        (&mut first_slice[first_index], &mut second_slice[0])
-    } else {
-        (&mut second_slice[0], &mut first_slice[second_index])
+/// Find the closest enemy in the player's FOV, up to a maximum range
+fn closest_monster(
+    fov: &FovMap,
+    objects: &[Object],
+    max_range: i32
+) -> Option<usize> {
+    let mut closest_enemy = None;
+    // Start with (slightly more than) the maximum range
+    let mut closest_distance = (max_range + 1) as f32;
+
+    for (id, object) in objects.iter().enumerate() {
+        if id != PLAYER
+            && object.fighter.is_some()
+            && object.ai.is_some()
+            && fov.is_in_fov(object.x, object.y) {
+            // Calculate the distance between this object and the player
+            let distance = objects[PLAYER].distance_to(object);
+            if distance < closest_distance {
+                // It's closer, so remember it
+                closest_enemy = Some(id);
+                closest_distance = distance;
+            }
+        }
     }
+
+    closest_enemy
 }
 
-fn player_death(player: &mut Object, game: &mut Game) {
+fn player_death(player: &mut Object, game: &mut Game) -> i32 {
     // The game ended!
-    game.messages.add("You died!", RED);
+    game.messages.add("You died!", RED, MessageCategory::Combat);
 
     // For added effect, transform the player into a corpse!
     player.sprite = '%';
     player.colour = DARK_RED;
+
+    0
 }
 
-fn monster_death(monster: &mut Object, game: &mut Game) {
+fn monster_death(monster: &mut Object, game: &mut Game) -> i32 {
+    let xp = monster.fighter.map_or(0, |f| f.xp);
+
     // Transform it into a nasty corpse!
     // It doesn't block, can't be attacked, and doesn't move
     game.messages.add(
-        format!("{} is dead!", monster.name),
-        GREEN
+        format!(
+            "{} is dead! You gain {} experience points.",
+            monster.name,
+            xp
+        ),
+        GREEN,
+        MessageCategory::Combat,
     );
     monster.sprite = '%';
     monster.colour = DARK_RED;
@@ -924,6 +4425,9 @@ fn monster_death(monster: &mut Object, game: &mut Game) {
     monster.fighter = None;
     monster.ai = None;
     monster.name = format!("remains of {}", monster.name);
+    game.kills += 1;
+
+    xp
 }
 
 fn render_bar(
@@ -996,3 +4500,790 @@ fn get_names_under_mouse(
 
     names.join(", ")
 }
+
+/// Show a window with a header and a lettered list of options, and wait for
+/// the player to choose one of them.
+///
+/// Returns the index of the chosen option, or `None` if any other key was
+/// pressed.
+fn menu<T: AsRef<str>>(
+    header: &str,
+    options: &[T],
+    width: i32,
+    root: &mut Root,
+) -> Option<usize> {
+    assert!(
+        options.len() <= MAX_INVENTORY_SIZE,
+        "Cannot have a menu with more than {} options.",
+        MAX_INVENTORY_SIZE
+    );
+
+    // Calculate the total height for the header (after auto-wrap) and one line
+    // per option
+    let header_height = if header.is_empty() {
+        0
+    } else {
+        root.get_height_rect(0, 0, width, SCREEN_HEIGHT, header)
+    };
+    let height = options.len() as i32 + header_height;
+
+    // Create an off-screen console that represents the menu's window
+    let mut window = Offscreen::new(width, height);
+
+    // Print the header, with auto-wrap
+    window.set_default_foreground(WHITE);
+    window.print_rect_ex(
+        0,
+        0,
+        width,
+        height,
+        BackgroundFlag::None,
+        TextAlignment::Left,
+        header,
+    );
+
+    // Print all the options
+    for (index, option_text) in options.iter().enumerate() {
+        let menu_letter = (b'a' + index as u8) as char;
+        let text = format!("({}) {}", menu_letter, option_text.as_ref());
+        window.print_ex(
+            0,
+            header_height + index as i32,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            text,
+        );
+    }
+
+    // Blit the contents of `window` to the root console, on top of the map
+    // that was already drawn there
+    let x = SCREEN_WIDTH / 2 - width / 2;
+    let y = SCREEN_HEIGHT / 2 - height / 2;
+    blit(&window, (0, 0), (width, height), root, (x, y), 1.0, 0.7);
+
+    // Present the root console to the player and wait for a key-press
+    root.flush();
+    let key = root.wait_for_keypress(true);
+
+    // Convert the ASCII code to an index; if it corresponds to an option,
+    // return it
+    if key.printable.is_ascii_alphabetic() {
+        let index = key.printable.to_ascii_lowercase() as usize - 'a' as usize;
+        if index < options.len() {
+            Some(index)
+        } else {
+            None
+        }
+    } else {
+        None
+    }
+}
+
+/// Show a message in a window, and wait for any key
+fn msgbox(text: &str, width: i32, root: &mut Root) {
+    let options: &[&str] = &[];
+    menu(text, options, width, root);
+}
+
+/// Show every message so far on the whole screen, each with the turn it was
+/// added on, scrolling with the movement keys and Page Up/Down until Escape.
+/// Tab goes through the message categories, to show only one of them.
+fn message_log(tcod: &mut Tcod, game: &Game) {
+    let page = (SCREEN_HEIGHT - 4) as usize;
+    let mut filter: Option<MessageCategory> = None;
+    let mut lines = message_log_lines(game, filter);
+
+    // Start at the newest messages, at the bottom
+    let mut last_top = lines.len().saturating_sub(page);
+    let mut top = last_top;
+
+    loop {
+        let root = &mut tcod.root;
+        root.set_default_background(BLACK);
+        root.clear();
+        root.set_default_foreground(WHITE);
+        let shown = match filter {
+            Some(category) => format!("{:?}", category),
+            None => "All".to_string(),
+        };
+        root.print_ex(
+            0,
+            0,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            format!("Message log (turn {}) - {}", game.turn, shown),
+        );
+        for (row, (turn, line, colour)) in
+            lines.iter().skip(top).take(page).enumerate()
+        {
+            let y = row as i32 + 2;
+            if let Some(turn) = turn {
+                root.set_default_foreground(DARK_GREY);
+                root.print_ex(
+                    LOG_TEXT_X - 2,
+                    y,
+                    BackgroundFlag::None,
+                    TextAlignment::Right,
+                    turn.to_string(),
+                );
+            }
+            root.set_default_foreground(*colour);
+            root.print(LOG_TEXT_X, y, line);
+        }
+        root.set_default_foreground(LIGHT_GREY);
+        root.print_ex(
+            0,
+            SCREEN_HEIGHT - 1,
+            BackgroundFlag::None,
+            TextAlignment::Left,
+            "Up/Down, PageUp/PageDown, Home/End to scroll, Tab to filter, \
+            Escape to close",
+        );
+        root.flush();
+
+        let key = root.wait_for_keypress(true);
+        let command = tcod.keymap.command(key);
+        top = match key.code {
+            KeyCode::Escape => break,
+            _ if command == Some(Command::MessageLog) => break,
+            KeyCode::Tab => {
+                // All, then each category in turn, then all again
+                filter = match filter {
+                    None => Some(MessageCategory::ALL[0]),
+                    Some(category) => MessageCategory::ALL
+                        .iter()
+                        .skip_while(|&&other| other != category)
+                        .nth(1)
+                        .copied(),
+                };
+                lines = message_log_lines(game, filter);
+                last_top = lines.len().saturating_sub(page);
+                last_top
+            },
+            KeyCode::Up => top.saturating_sub(1),
+            KeyCode::Down => top + 1,
+            KeyCode::PageUp => top.saturating_sub(page),
+            KeyCode::PageDown => top + page,
+            KeyCode::Home => 0,
+            KeyCode::End => last_top,
+            _ => match command.and_then(Command::direction) {
+                Some((_, dy)) if dy < 0 => top.saturating_sub(1),
+                Some((_, dy)) if dy > 0 => top + 1,
+                _ => top,
+            },
+        }
+        .min(last_top);
+    }
+}
+
+/// The lines of the message log, showing only the messages of one category
+/// if there is a filter. The first line of each message is labelled with its
+/// turn.
+fn message_log_lines(
+    game: &Game,
+    filter: Option<MessageCategory>,
+) -> Vec<(Option<u32>, String, Color)> {
+    let mut lines = vec![];
+    let shown = game
+        .messages
+        .iter()
+        .filter(|message| filter.is_none_or(|shown| message.category == shown));
+    for message in shown {
+        let text = message.full_text();
+        let wrapped = wrap_text(&text, SCREEN_WIDTH - LOG_TEXT_X);
+        for (index, line) in wrapped.into_iter().enumerate() {
+            let turn = if index == 0 { Some(message.turn) } else { None };
+            lines.push((turn, line, message.colour));
+        }
+    }
+    lines
+}
+
+/// Split the text into lines of at most `width` characters, breaking between
+/// words where it can
+fn wrap_text(text: &str, width: i32) -> Vec<String> {
+    let width = width as usize;
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word = word;
+        let line_length = line.chars().count();
+        if line_length > 0 && line_length + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        // Words longer than a line are broken up
+        while word.chars().count() > width {
+            let split = word.char_indices().nth(width).map_or(0, |(i, _)| i);
+            lines.push(word[..split].to_string());
+            word = &word[split..];
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+fn inventory_menu(
+    inventory: &[Object],
+    header: &str,
+    root: &mut Root
+) -> Option<usize> {
+    // Show a menu with each item of the inventory as an option
+    let options = if inventory.is_empty() {
+        vec!["Inventory is empty.".into()]
+    } else {
+        inventory
+            .iter()
+            .map(|item| {
+                // Show additional information, in case it's equipped
+                match item.equipment {
+                    Some(equipment) if equipment.equipped => {
+                        format!("{} (on {})", item.name, equipment.slot)
+                    },
+                    _ => item.name.clone(),
+                }
+            })
+            .collect()
+    };
+
+    let inventory_index = menu(header, &options, INVENTORY_WIDTH, root);
+
+    // If an item was chosen, return it
+    if !inventory.is_empty() {
+        inventory_index
+    } else {
+        None
+    }
+}
+
+/// Let the player pick a tile in their FOV (and optionally within a range of
+/// them), either with the mouse or by moving a cursor with the movement keys.
+///
+/// Returns the position of the tile that was left-clicked or confirmed with
+/// Enter, or `None` if the player cancelled with Escape or a right-click.
+fn target_tile(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &[Object],
+    max_range: Option<f32>,
+) -> Option<(i32, i32)> {
+    // The cursor starts on the player, and then follows the mouse or the keys
+    let (mut cursor_x, mut cursor_y) = objects[PLAYER].pos();
+    let mut mouse_position = (tcod.mouse.cx as i32, tcod.mouse.cy as i32);
+
+    loop {
+        // Render the screen. This erases any menu that was open and shows the
+        // names of the objects under the mouse.
+        tcod.con.clear();
+        render_all(tcod, game, objects);
+
+        // Highlight the tile under the cursor
+        let valid =
+            is_valid_target(cursor_x, cursor_y, &tcod.fov, objects, max_range);
+        tcod.root.set_char_background(
+            cursor_x,
+            cursor_y,
+            if valid {
+                COLOUR_VALID_TARGET
+            } else {
+                COLOUR_INVALID_TARGET
+            },
+            BackgroundFlag::Set,
+        );
+        tcod.root.flush();
+
+        let mut clicked = false;
+        tcod.key = Default::default();
+        match input::check_for_event(input::MOUSE | input::KEY_PRESS) {
+            Some((_, Event::Mouse(m))) => {
+                tcod.mouse = m;
+                if m.rbutton_pressed {
+                    // Cancel if the player right-clicked
+                    return None;
+                }
+
+                // Only follow the mouse when it moves over the map, so that it
+                // doesn't fight with the movement keys
+                let position = (m.cx as i32, m.cy as i32);
+                let on_map = (0..MAP_WIDTH).contains(&position.0)
+                    && (0..MAP_HEIGHT).contains(&position.1);
+                if on_map && (position != mouse_position || m.lbutton_pressed)
+                {
+                    cursor_x = position.0;
+                    cursor_y = position.1;
+                    clicked = m.lbutton_pressed;
+                }
+                mouse_position = position;
+            },
+            Some((_, Event::Key(k))) => tcod.key = k,
+            _ => {},
+        }
+
+        // Move the cursor with the movement keys, without leaving the map
+        let (dx, dy) = tcod
+            .keymap
+            .command(tcod.key)
+            .and_then(Command::direction)
+            .unwrap_or((0, 0));
+        cursor_x = (cursor_x + dx).clamp(0, MAP_WIDTH - 1);
+        cursor_y = (cursor_y + dy).clamp(0, MAP_HEIGHT - 1);
+
+        match tcod.key.code {
+            // Cancel if the player pressed Escape
+            KeyCode::Escape => return None,
+            KeyCode::Enter => clicked = true,
+            _ => {},
+        }
+
+        if clicked
+            && is_valid_target(
+                cursor_x,
+                cursor_y,
+                &tcod.fov,
+                objects,
+                max_range,
+            ) {
+            return Some((cursor_x, cursor_y));
+        }
+    }
+}
+
+/// Look around the map with a cursor that moves with the movement keys, or
+/// jumps between the monsters and items in view with Tab, describing what is
+/// under it, until Escape.
+fn look_mode(tcod: &mut Tcod, game: &Game, objects: &[Object]) {
+    // The monsters and items in view, nearest first
+    let player = &objects[PLAYER];
+    let mut in_view: Vec<&Object> = objects
+        .iter()
+        .filter(|object| {
+            (object.alive || object.item.is_some())
+                && object.pos() != player.pos()
+                && tcod.fov.is_in_fov(object.x, object.y)
+        })
+        .collect();
+    in_view.sort_by(|a, b| {
+        player.distance_to(a).total_cmp(&player.distance_to(b))
+    });
+    let mut next_in_view = 0;
+
+    // The cursor starts on the player
+    let (mut cursor_x, mut cursor_y) = player.pos();
+
+    loop {
+        tcod.con.clear();
+        render_all(tcod, game, objects);
+        tcod.root.set_char_background(
+            cursor_x,
+            cursor_y,
+            COLOUR_LOOK_CURSOR,
+            BackgroundFlag::Set,
+        );
+
+        // Show the description on the other side of the screen from the
+        // cursor, so that it doesn't cover it
+        let description =
+            describe_tile(cursor_x, cursor_y, &tcod.fov, game, objects);
+        let height = tcod.root.get_height_rect(
+            0,
+            0,
+            LOOK_WIDTH,
+            SCREEN_HEIGHT,
+            &description,
+        );
+        let mut window = Offscreen::new(LOOK_WIDTH, height);
+        window.set_default_foreground(WHITE);
+        window.print_rect(0, 0, LOOK_WIDTH, height, &description);
+        let x = if cursor_x < MAP_WIDTH / 2 {
+            MAP_WIDTH - LOOK_WIDTH - 1
+        } else {
+            1
+        };
+        blit(
+            &window,
+            (0, 0),
+            (LOOK_WIDTH, height),
+            &mut tcod.root,
+            (x, 1),
+            1.0,
+            0.7,
+        );
+        tcod.root.flush();
+
+        let key = tcod.root.wait_for_keypress(true);
+        let command = tcod.keymap.command(key);
+        match key.code {
+            KeyCode::Escape | KeyCode::Enter => break,
+            _ if command == Some(Command::Look) => break,
+            KeyCode::Tab if !in_view.is_empty() => {
+                let object = in_view[next_in_view % in_view.len()];
+                (cursor_x, cursor_y) = object.pos();
+                next_in_view += 1;
+            },
+            _ => {
+                // Move the cursor with the movement keys, without leaving the
+                // map
+                let (dx, dy) =
+                    command.and_then(Command::direction).unwrap_or((0, 0));
+                cursor_x = (cursor_x + dx).clamp(0, MAP_WIDTH - 1);
+                cursor_y = (cursor_y + dy).clamp(0, MAP_HEIGHT - 1);
+            },
+        }
+    }
+}
+
+/// Describe the tile and whatever is on it that the player can see, or
+/// remembers if it is out of view
+fn describe_tile(
+    x: i32,
+    y: i32,
+    fov: &FovMap,
+    game: &Game,
+    objects: &[Object],
+) -> String {
+    let tile = &game.map[x as usize][y as usize];
+    if !tile.explored {
+        return "Unexplored".to_string();
+    }
+
+    let in_fov = fov.is_in_fov(x, y);
+    let mut description = format!(
+        "{} ({})",
+        if tile.blocked { "Wall" } else { "Floor" },
+        if in_fov { "in view" } else { "remembered" },
+    );
+
+    // Only what is in view, or always drawn once seen (like the stairs), is
+    // known to be there
+    let seen = objects.iter().filter(|object| {
+        object.pos() == (x, y) && (in_fov || object.always_visible)
+    });
+    for object in seen {
+        description.push_str(&format!("\n\n{}", object.name));
+        if let Some(fighter) = object.fighter {
+            let max_hp = object.max_hp(game);
+            description.push_str(&format!(
+                "\n{}\nHP {}/{}, power {}, defence {}",
+                health_state(fighter.hp, max_hp),
+                fighter.hp,
+                max_hp,
+                object.power(game),
+                object.defence(game),
+            ));
+            if let Some(Ai::Confused { .. }) = object.ai {
+                description.push_str("\nConfused");
+            }
+        }
+        if let Some(item) = object.item {
+            description.push_str(&format!("\n{}", item.description()));
+        }
+        if let Some(equipment) = object.equipment {
+            description.push_str(&format!(
+                "\nPower +{}, defence +{}, max HP +{}",
+                equipment.power_bonus,
+                equipment.defence_bonus,
+                equipment.max_hp_bonus,
+            ));
+        }
+    }
+    description
+}
+
+/// How hurt a fighter looks, from how much of their maximum HP they have left
+fn health_state(hp: i32, max_hp: i32) -> &'static str {
+    match hp * 100 / cmp::max(max_hp, 1) {
+        100.. => "Unhurt",
+        75.. => "Lightly wounded",
+        50.. => "Wounded",
+        25.. => "Badly wounded",
+        _ => "Almost dead",
+    }
+}
+
+/// Only tiles in FOV and, if a range is specified, in that range, can be
+/// targeted
+fn is_valid_target(
+    x: i32,
+    y: i32,
+    fov: &FovMap,
+    objects: &[Object],
+    max_range: Option<f32>,
+) -> bool {
+    let on_map = (0..MAP_WIDTH).contains(&x) && (0..MAP_HEIGHT).contains(&y);
+    let in_fov = on_map && fov.is_in_fov(x, y);
+    let in_range = max_range.is_none_or(|range| {
+        objects[PLAYER].distance(x, y) <= range
+    });
+    in_fov && in_range
+}
+
+/// Whether the target is of the given kind and could be picked in targeting
+/// mode
+fn is_reachable_target(
+    target: Target,
+    target_kind: TargetKind,
+    fov: &FovMap,
+    objects: &[Object],
+) -> bool {
+    match (target, target_kind) {
+        (Target::Tile(x, y), TargetKind::Tile { max_range }) => {
+            is_valid_target(x, y, fov, objects, max_range)
+        },
+        (Target::Monster(id), TargetKind::Monster { max_range }) => {
+            id != PLAYER
+                && objects.get(id).is_some_and(|object| {
+                    object.fighter.is_some()
+                        && is_valid_target(
+                            object.x,
+                            object.y,
+                            fov,
+                            objects,
+                            max_range,
+                        )
+                })
+        },
+        _ => false,
+    }
+}
+
+/// Let the player pick a monster in their FOV (and optionally within a range
+/// of them).
+///
+/// Returns the id of the chosen monster, or `None` if the player cancelled.
+fn target_monster(
+    tcod: &mut Tcod,
+    game: &mut Game,
+    objects: &[Object],
+    max_range: Option<f32>,
+) -> Option<usize> {
+    loop {
+        match target_tile(tcod, game, objects, max_range) {
+            Some((x, y)) => {
+                // Return the first monster that was chosen, otherwise keep
+                // targeting
+                for (id, object) in objects.iter().enumerate() {
+                    if object.pos() == (x, y)
+                        && object.fighter.is_some()
+                        && id != PLAYER {
+                        return Some(id);
+                    }
+                }
+            },
+            None => return None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: u32 = 1234;
+
+    /// Moves, waits, exploring and travelling back to the start
+    fn script(start: (i32, i32)) -> Vec<PlayerAction> {
+        use PlayerAction::*;
+        vec![
+            MoveOrAttack(1, 0),
+            MoveOrAttack(0, 1),
+            MoveOrAttack(-1, 1),
+            Wait,
+            Explore,
+            MoveOrAttack(0, -1),
+            Wait,
+            Explore,
+            Travel(start.0, start.1),
+            Wait,
+            Explore,
+        ]
+    }
+
+    /// Play the actions as the headless driver does, repeating travelling
+    /// and exploring (up to a point) until they stop taking turns
+    fn play(simulation: &mut Simulation, actions: &[PlayerAction]) {
+        for &action in actions {
+            for _ in 0..100 {
+                let result = simulation.step(action);
+                if result == TurnResult::DidntTakeTurn || !action.repeats() {
+                    break;
+                }
+            }
+        }
+    }
+
+    /// Put the player on the stairs and go down them, which generates the next
+    /// level from the game's random numbers
+    fn descend(simulation: &mut Simulation) {
+        let stairs = simulation
+            .objects
+            .iter()
+            .find(|object| object.name == "stairs")
+            .map(Object::pos)
+            .expect("every level has stairs");
+        simulation.objects[PLAYER].set_pos(stairs.0, stairs.1);
+        simulation.step(PlayerAction::Descend);
+    }
+
+    /// Everything the two runs of a game are compared on
+    #[derive(Debug, PartialEq)]
+    struct Snapshot {
+        objects: Vec<(String, (i32, i32), Option<i32>)>,
+        messages: Vec<String>,
+        turn: u32,
+        draws: u64,
+    }
+
+    fn snapshot(simulation: &Simulation) -> Snapshot {
+        Snapshot {
+            objects: simulation
+                .objects
+                .iter()
+                .map(|object| {
+                    let hp = object.fighter.map(|fighter| fighter.hp);
+                    (object.name.clone(), object.pos(), hp)
+                })
+                .collect(),
+            messages: simulation
+                .game
+                .messages
+                .iter()
+                .map(Message::full_text)
+                .collect(),
+            turn: simulation.game.turn,
+            draws: simulation.game.rng.draws,
+        }
+    }
+
+    #[test]
+    fn the_same_seed_and_actions_give_the_same_game() {
+        let mut first = Simulation::new(SEED);
+        let mut second = Simulation::new(SEED);
+        let start = first.objects[PLAYER].pos();
+        let draws = first.game.rng.draws;
+
+        for simulation in [&mut first, &mut second] {
+            play(simulation, &script(start));
+            descend(simulation);
+            let start = simulation.objects[PLAYER].pos();
+            play(simulation, &script(start));
+        }
+
+        assert_eq!(first.game.dungeon_level, 2);
+        assert!(first.game.rng.draws > draws);
+        assert_eq!(snapshot(&first), snapshot(&second));
+    }
+
+    #[test]
+    fn a_saved_and_loaded_game_plays_on_the_same() {
+        let mut played = Simulation::new(SEED);
+        let start = played.objects[PLAYER].pos();
+        let script = script(start);
+        let (before, after) = script.split_at(script.len() / 2);
+
+        play(&mut played, before);
+        descend(&mut played);
+        let save_data = to_save_data(&played.game, &played.objects)
+            .expect("the game can be saved");
+        let (game, objects) = from_save_data(&save_data)
+            .expect("the saved game can be loaded");
+        let mut loaded = Simulation::resume(game, objects);
+        assert_eq!(snapshot(&played), snapshot(&loaded));
+
+        play(&mut played, after);
+        play(&mut loaded, after);
+        assert_eq!(snapshot(&played), snapshot(&loaded));
+    }
+
+    #[test]
+    fn moving_takes_a_turn_and_moves_the_player() {
+        let mut simulation = Simulation::new(SEED);
+        let (x, y) = simulation.objects[PLAYER].pos();
+        let (dx, dy) = neighbours(x, y)
+            .map(|(nx, ny)| (nx - x, ny - y))
+            .find(|&(dx, dy)| {
+                !is_blocked(
+                    x + dx,
+                    y + dy,
+                    &simulation.game.map,
+                    &simulation.objects,
+                )
+            })
+            .expect("the player should be able to move somewhere");
+
+        let result = simulation.step(PlayerAction::MoveOrAttack(dx, dy));
+
+        assert_eq!(result, TurnResult::TookTurn);
+        assert_eq!(simulation.objects[PLAYER].pos(), (x + dx, y + dy));
+        assert_eq!(simulation.game.turn, 2);
+    }
+
+    #[test]
+    fn waiting_takes_a_turn_and_sees_the_surroundings() {
+        let mut simulation = Simulation::new(SEED);
+        let (x, y) = simulation.objects[PLAYER].pos();
+
+        let result = simulation.step(PlayerAction::Wait);
+
+        assert_eq!(result, TurnResult::TookTurn);
+        assert_eq!(simulation.objects[PLAYER].pos(), (x, y));
+        assert_eq!(simulation.game.turn, 2);
+        assert!(simulation.game.map[x as usize][y as usize].explored);
+    }
+
+    #[test]
+    fn descending_off_the_stairs_does_nothing() {
+        let mut simulation = Simulation::new(SEED);
+        let on_stairs = simulation.objects.iter().any(|object| {
+            object.name == "stairs"
+                && object.pos() == simulation.objects[PLAYER].pos()
+        });
+        assert!(!on_stairs);
+
+        let result = simulation.step(PlayerAction::Descend);
+
+        assert_eq!(result, TurnResult::DidntTakeTurn);
+        assert_eq!(simulation.game.dungeon_level, 1);
+        assert_eq!(simulation.game.turn, 1);
+    }
+
+    #[test]
+    fn every_vault_entrance_opens_onto_its_floor() {
+        for (index, vault) in VAULTS.iter().enumerate() {
+            for quarter_turns in 0..4 {
+                for mirrored in [false, true] {
+                    let layout = vault.layout(quarter_turns, mirrored);
+                    let (row, column) = layout
+                        .iter()
+                        .enumerate()
+                        .find_map(|(row, tiles)| {
+                            let column =
+                                tiles.iter().position(|&tile| tile == '+')?;
+                            Some((row as i32, column as i32))
+                        })
+                        .expect("every vault has an entrance");
+
+                    let inside = [(-1, 0), (1, 0), (0, -1), (0, 1)]
+                        .iter()
+                        .filter_map(|&(dx, dy)| {
+                            let tiles = layout.get((row + dy) as usize)?;
+                            tiles.get((column + dx) as usize)
+                        })
+                        .any(|&tile| !matches!(tile, '#' | ' ' | '+'));
+                    assert!(
+                        inside,
+                        "vault {} (turned {}, mirrored {}) has a blocked \
+                        entrance",
+                        index,
+                        quarter_turns,
+                        mirrored
+                    );
+                }
+            }
+        }
+    }
+}